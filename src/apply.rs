@@ -0,0 +1,240 @@
+extern crate serde_json;
+
+use cert_machine::{
+    all_cert_types, current_cert, is_cert_of, link_ca_certs, profile, serial_hex, CertType, Config, Database, Error,
+    RevocationReason, Status, Store, CA,
+};
+use create_ca;
+use kubernetes_certs::{create_directory_struct, gen_cert, gen_service_account_keys};
+use openssl::bn::BigNum;
+use revocation::write_crls;
+
+// Certificate defined by config when it was applied last time.
+// Kept in "<out_dir>/applied.json" to find certificates of entries removed from config.
+#[derive(Serialize, Deserialize)]
+struct Applied {
+    name: String,
+    ca: String,
+    filename: String,
+    common_name: String,
+    // Symlinks and kubeconfigs installed for certificate
+    files: Vec<String>,
+}
+
+impl Applied {
+    fn new(config: &Config, cert_type: &CertType) -> Applied {
+        let profile = profile(&config, &cert_type);
+        let mut files = vec![];
+        if let Some(ref symlink) = profile.symlink {
+            files.push(format!("{}.crt", &symlink));
+            files.push(format!("{}.key", &symlink));
+        }
+        if let Some(ref kubeconfig) = profile.kubeconfig {
+            files.push(kubeconfig.filename.clone());
+        }
+        for &(_, ref destination) in profile.links.iter() {
+            files.push(destination.clone());
+        }
+        // CA certificates linked into instance directory by link_ca_certs
+        match *cert_type {
+            CertType::Kubelet(instance) => files.push(format!("{}/{}/ca.crt", &config.out_dir, instance.dirname())),
+            CertType::EtcdServer(instance) => {
+                files.push(format!("{}/{}/etcd-ca.crt", &config.out_dir, instance.dirname()))
+            }
+            _ => {}
+        }
+        Applied {
+            name: cert_type.name(),
+            ca: profile.authority.dir_name().to_owned(),
+            filename: profile.filename.clone(),
+            common_name: profile.common_name.clone(),
+            files,
+        }
+    }
+}
+
+// Brings output directory in line with config: creates CA if there is none, issues missing certificates,
+// reissues ones whose subject or SAN differ from config and revokes certificates of removed entries.
+// Certificates which match config are left as is, so running it again changes nothing.
+pub fn apply(store: &dyn Store, config: &Config) -> Result<(), Error> {
+    create_directory_struct(store, &config, &config.out_dir)?;
+    let ca = match store.exists(&format!("{}/CA/root/certs/ca.crt", &config.out_dir)) {
+        true => CA::read(store, &config.out_dir)?,
+        false => {
+            let ca = create_ca(store, &config)?;
+            write_crls(store, &config, &ca)?;
+            ca
+        }
+    };
+    if !store.exists(&format!("{}/sa.key", &config.out_dir)) {
+        gen_service_account_keys(store, &config, &config.out_dir)?;
+    }
+
+    let mut revoked = false;
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        match current_cert(store, &profile)? {
+            None => {
+                gen_cert(store, &ca, &config, &cert_type)?;
+            }
+            Some(ref bundle) if !profile.matches(&bundle.cert)? => {
+                println!("Subject or SAN of {} cert differs from config", &profile.description);
+                gen_cert(store, &ca, &config, &cert_type)?;
+                let serial = bundle.cert.serial_number().to_bn()?;
+                Database::open(store, &profile.ca_dir())?.revoke(store, &serial, RevocationReason::Superseded)?;
+                println!("Revoked certificate {} ({})", serial_hex(&serial)?, RevocationReason::Superseded.name());
+                revoked = true;
+            }
+            Some(_) => {}
+        }
+    }
+    link_ca_certs(store, &config)?;
+
+    let applied = applied(&config);
+    let previous: Vec<Applied> = match store.exists(&applied_filename(&config)) {
+        true => match serde_json::from_slice(&store.read(&applied_filename(&config))?) {
+            Ok(previous) => previous,
+            Err(err) => return Err(Error::Index(format!("Unable to read {}: {}", applied_filename(&config), err))),
+        },
+        false => vec![],
+    };
+    for removed in previous.iter().filter(|old| !applied.iter().any(|new| new.name == old.name)) {
+        println!("Removing {}: it is not in config anymore", &removed.name);
+        if revoke_removed(store, &config, &removed)? {
+            revoked = true;
+        }
+        for filename in removed.files.iter() {
+            if store.is_link(&filename) || store.exists(&filename) {
+                store.remove(&filename)?;
+            }
+        }
+    }
+    if revoked {
+        write_crls(store, &config, &ca)?;
+    }
+    save_applied(store, &config)
+}
+
+fn applied_filename(config: &Config) -> String {
+    format!("{}/applied.json", &config.out_dir)
+}
+
+fn applied(config: &Config) -> Vec<Applied> {
+    all_cert_types(&config).iter().map(|cert_type| Applied::new(&config, &cert_type)).collect()
+}
+
+// Records certificates defined by config, next apply revokes ones removed from config since then
+pub fn save_applied(store: &dyn Store, config: &Config) -> Result<(), Error> {
+    match serde_json::to_string_pretty(&applied(&config)) {
+        Ok(contents) => store.write(&applied_filename(&config), contents.as_bytes()),
+        Err(err) => Err(Error::Index(format!("Unable to write {}: {}", applied_filename(&config), err))),
+    }
+}
+
+// Revokes valid certificates of removed entry, they stay in CA directory as archive
+fn revoke_removed(store: &dyn Store, config: &Config, removed: &Applied) -> Result<bool, Error> {
+    let ca_dir = format!("{}/CA/{}", &config.out_dir, &removed.ca);
+    let mut database = Database::open(store, &ca_dir)?;
+    let mut serials = vec![];
+    for entry in database.entries.iter().filter(|entry| entry.status == Status::Valid) {
+        if is_cert_of(&entry.filename, &removed.filename) && entry.has_common_name(&removed.common_name) {
+            serials.push(BigNum::from_hex_str(&entry.serial)?);
+        }
+    }
+    let reason = RevocationReason::CessationOfOperation;
+    for serial in serials.iter() {
+        database.revoke(store, &serial, reason)?;
+        println!("Revoked certificate {} ({})", serial_hex(&serial)?, reason.name());
+    }
+    Ok(!serials.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::MemoryStore;
+    use testing;
+
+    const S2: &str = "[[worker]]\nhostname = \"s2\"\nsan = [\"10.0.22.3\", \"s2\"]";
+
+    // Serials of current kubelet certificates of instance: client, then server
+    fn kubelet_serials(store: &dyn Store, config: &Config, hostname: &str) -> Vec<String> {
+        let instance = config.worker.iter().find(|instance| instance.hostname == hostname).unwrap();
+        let cert_types = [CertType::Kubelet(&instance), CertType::KubeletServer(&instance)];
+        let mut serials = vec![];
+        for cert_type in cert_types.iter() {
+            let bundle = current_cert(store, &profile(&config, &cert_type)).unwrap().unwrap();
+            serials.push(serial_hex(&bundle.cert.serial_number().to_bn().unwrap()).unwrap());
+        }
+        serials
+    }
+
+    fn status(store: &dyn Store, serial: &str) -> (Status, Option<RevocationReason>) {
+        let database = Database::open(store, "out/CA/root").unwrap();
+        let entry = database.find(&BigNum::from_hex_str(&serial).unwrap()).unwrap().unwrap();
+        (entry.status, entry.revocation().unwrap().map(|(_, reason)| reason))
+    }
+
+    #[test]
+    fn add_entry() {
+        let store = MemoryStore::new();
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        apply(&store, &config).unwrap();
+        let s1 = kubelet_serials(&store, &config, "s1");
+        let entries = Database::open(&store, "out/CA/root").unwrap().entries.len();
+
+        let config = Config::from_toml(&testing::config(S2)).unwrap();
+        apply(&store, &config).unwrap();
+        assert_eq!(kubelet_serials(&store, &config, "s1"), s1);
+        let s2 = kubelet_serials(&store, &config, "s2");
+        assert_eq!(status(&store, &s2[0]).0, Status::Valid);
+        assert!(store.exists("out/s2/ca.crt"));
+        assert_eq!(Database::open(&store, "out/CA/root").unwrap().entries.len(), entries + 2);
+
+        // Nothing changes when config is applied again
+        apply(&store, &config).unwrap();
+        assert_eq!(Database::open(&store, "out/CA/root").unwrap().entries.len(), entries + 2);
+    }
+
+    #[test]
+    fn change_entry() {
+        let store = MemoryStore::new();
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        apply(&store, &config).unwrap();
+        let s1 = kubelet_serials(&store, &config, "s1");
+        let crl_number = store.read("out/CA/root/crlnumber").unwrap();
+
+        let changed = testing::config("").replace("\"10.0.22.2\", \"s1\"", "\"10.0.22.4\", \"s1\"");
+        let config = Config::from_toml(&changed).unwrap();
+        apply(&store, &config).unwrap();
+        let reissued = kubelet_serials(&store, &config, "s1");
+        // Client certificate has no SAN, only server one is reissued
+        assert_eq!(reissued[0], s1[0]);
+        assert_ne!(reissued[1], s1[1]);
+        assert_eq!(status(&store, &s1[1]), (Status::Revoked, Some(RevocationReason::Superseded)));
+        assert_eq!(status(&store, &reissued[1]).0, Status::Valid);
+        assert_ne!(store.read("out/CA/root/crlnumber").unwrap(), crl_number);
+    }
+
+    #[test]
+    fn remove_entry() {
+        let store = MemoryStore::new();
+        let config = Config::from_toml(&testing::config(S2)).unwrap();
+        apply(&store, &config).unwrap();
+        let s1 = kubelet_serials(&store, &config, "s1");
+        let s2 = kubelet_serials(&store, &config, "s2");
+
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        apply(&store, &config).unwrap();
+        for serial in s2.iter() {
+            assert_eq!(status(&store, &serial), (Status::Revoked, Some(RevocationReason::CessationOfOperation)));
+        }
+        assert_eq!(kubelet_serials(&store, &config, "s1"), s1);
+        assert_eq!(status(&store, &s1[1]).0, Status::Valid);
+        assert!(!store.exists("out/s2/node.crt"));
+        assert!(!store.exists("out/s2/node.kubeconfig"));
+        assert!(!store.exists("out/s2/ca.crt"));
+        // Revoked certificates stay in CA directory
+        assert!(store.list("out/CA/root/certs").unwrap().iter().any(|name| name.contains("s2")));
+    }
+}
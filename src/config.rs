@@ -0,0 +1,512 @@
+extern crate toml;
+
+use openssl::asn1::Asn1Time;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use AltName;
+use Authority;
+use Error;
+use KeyAlgorithm;
+use seconds_between;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub cluster_name: String,
+    pub worker: Vec<Instance>,
+    pub etcd_server: Vec<Instance>,
+    pub user: Option<Vec<User>>,
+    pub etcd_users: Option<Vec<String>>,
+    pub validity_days: u32,
+    #[serde(default = "cert_key_size")]
+    pub key_size: u32,
+    #[serde(default = "key_algorithm")]
+    pub key_algorithm: KeyAlgorithm,
+    // Seconds not_before of issued certificates is set back, e.g. 300 for nodes with slightly slow clocks
+    #[serde(default = "not_before_skew_seconds")]
+    pub not_before_skew_seconds: u32,
+    // Absolute validity of leaf certificates as "YYYYMMDDHHMMSSZ", validity_days is used if not_after is not set.
+    // not_after applies to every leaf certificate, so entries can't set own validity_days with it.
+    pub not_before: Option<String>,
+    pub not_after: Option<String>,
+    // Leaf certificate expires no later than CA which issued it
+    #[serde(default = "cap_validity_at_ca")]
+    pub cap_validity_at_ca: bool,
+    pub ca: Ca,
+    pub master_san: Vec<String>,
+    // DNS domain of cluster, apiserver cert gets "kubernetes.default.svc.<cluster_domain>" SAN
+    #[serde(default = "cluster_domain")]
+    pub cluster_domain: String,
+    // Service IP ranges, IPv4 and/or IPv6. First IP of each range is the address of kubernetes service.
+    #[serde(default = "service_cidr")]
+    pub service_cidr: Vec<String>,
+    pub apiserver_internal_address: String,
+    pub apiserver_external_address: String,
+    #[serde(default = "serial_numbers")]
+    pub serial_numbers: SerialNumbers,
+    #[serde(default = "overwrite_false")]
+    pub overwrite: bool,
+    #[serde(default = "out_dir")]
+    pub out_dir: String,
+    // Settings of certificates of given kind, e.g. [components.apiserver]
+    #[serde(default)]
+    pub components: HashMap<String, Overrides>,
+}
+
+// Kinds of certificates which can have own settings in [components.<kind>]
+const COMPONENTS: &[&str] = &[
+    "admin",
+    "apiserver",
+    "apiserver-client",
+    "apiserver-etcd-client",
+    "controller-manager",
+    "scheduler",
+    "front-proxy-client",
+    "proxy",
+    "kubelet",
+    "etcd",
+    "etcd-user",
+    "user",
+];
+
+// Settings of leaf certificates which replace global ones for component, instance or user
+#[derive(Clone, Copy, Default, Deserialize)]
+pub struct Overrides {
+    pub validity_days: Option<u32>,
+    pub key_size: Option<u32>,
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+impl Overrides {
+    // Settings which are not set here are taken from other
+    pub fn or(self, other: Overrides) -> Overrides {
+        Overrides {
+            validity_days: self.validity_days.or(other.validity_days),
+            key_size: self.key_size.or(other.key_size),
+            key_algorithm: self.key_algorithm.or(other.key_algorithm),
+        }
+    }
+}
+
+// How serial numbers of issued certificates are chosen
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+pub enum SerialNumbers {
+    // 128 bit random number
+    #[serde(rename = "random")]
+    Random,
+    // Sequential counter kept in "CA/<name>/index"
+    #[serde(rename = "counter")]
+    Counter,
+}
+
+// Which private keys are encrypted with passphrase when written
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+pub enum KeyEncryption {
+    #[serde(rename = "none")]
+    None,
+    // CA keys only
+    #[serde(rename = "ca")]
+    Ca,
+    // CA and leaf keys
+    #[serde(rename = "all")]
+    All,
+}
+
+#[derive(Deserialize)]
+pub struct Instance {
+    pub filename: Option<String>,
+    pub hostname: String,
+    pub san: Vec<String>,
+    pub validity_days: Option<u32>,
+    pub key_size: Option<u32>,
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+#[derive(Deserialize)]
+pub struct User {
+    pub username: String,
+    // Written in config as "group", either one group or list of them
+    #[serde(rename = "group", default, deserialize_with = "one_or_many")]
+    pub groups: Vec<String>,
+    pub validity_days: Option<u32>,
+    pub key_size: Option<u32>,
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+#[derive(Deserialize)]
+pub struct Ca {
+    pub country: Option<String>,
+    pub organization: Option<String>,
+    pub organization_unit: Option<String>,
+    pub locality: Option<String>,
+    pub state_or_province_name:Option<String>,
+    pub validity_days: u32,
+    #[serde(default = "ca_key_size")]
+    pub key_size: u32,
+    #[serde(default = "key_algorithm")]
+    pub key_algorithm: KeyAlgorithm,
+    #[serde(default = "crl_next_update_days")]
+    pub crl_next_update_days: u32,
+    #[serde(default = "encrypt_keys")]
+    pub encrypt_keys: KeyEncryption,
+    // File with passphrase for private keys, CERT_MACHINE_PASSPHRASE and terminal prompt are used otherwise
+    pub passphrase_file: Option<String>,
+    pub pkcs11: Option<Pkcs11>,
+}
+
+// CA keys kept in PKCS#11 token, used through OpenSSL pkcs11 engine (libp11)
+#[derive(Deserialize)]
+pub struct Pkcs11 {
+    // Path to PKCS#11 module, e.g. /usr/lib/softhsm/libsofthsm2.so
+    pub module: String,
+    // Slot where keys are generated, first slot with token is used otherwise
+    pub slot: Option<u32>,
+    // Keys are labeled "<key_label>-<ca>"
+    pub key_label: String,
+    // File with user PIN, CERT_MACHINE_PKCS11_PIN and terminal prompt are used otherwise
+    pub pin_file: Option<String>,
+    // CAs which keys are kept in token
+    #[serde(default = "pkcs11_cas")]
+    pub cas: Vec<String>,
+    // Generate new CA keys inside token, otherwise keys must already be there
+    #[serde(default = "generate_true")]
+    pub generate: bool,
+}
+
+impl Instance {
+    // Directory name where certificates for this instance are stored
+    pub fn dirname(&self) -> &str {
+        match self.filename {
+            Some(ref filename) => filename,
+            None => &self.hostname,
+        }
+    }
+
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            validity_days: self.validity_days,
+            key_size: self.key_size,
+            key_algorithm: self.key_algorithm,
+        }
+    }
+}
+
+impl User {
+    pub fn new(username: &str, groups: Vec<String>) -> User {
+        User {
+            username: username.to_owned(),
+            groups,
+            validity_days: None,
+            key_size: None,
+            key_algorithm: None,
+        }
+    }
+
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            validity_days: self.validity_days,
+            key_size: self.key_size,
+            key_algorithm: self.key_algorithm,
+        }
+    }
+}
+
+impl Config {
+    pub fn new(filename: &str) -> Result<Box<Config>, Error> {
+        if !Path::new(&filename).exists() {
+        	return Err(Error::Config(format!("{} does not exists!", &filename)));
+        }
+        let mut config_file = File::open(filename)?;
+        let mut contents = String::new();
+
+        config_file.read_to_string(&mut contents)?;
+        Config::from_toml(&contents)
+    }
+
+    // Parses and checks config given as TOML document
+    pub fn from_toml(contents: &str) -> Result<Box<Config>, Error> {
+        let config: Config = match toml::from_str(&contents) {
+        	Err(err) => {
+        		return Err(Error::Config(format!("Config parse error: {}", err)));
+        	},
+        	Ok(config) => config,
+        };
+
+        // Check SAN entries before any certificate is issued
+        let instances = config.worker.iter().chain(config.etcd_server.iter());
+        for san in config.master_san.iter().chain(instances.flat_map(|i| i.san.iter())) {
+            AltName::parse(&san)?;
+        }
+        if config.cluster_domain.is_empty() {
+            return Err(Error::Config("cluster_domain must not be empty".to_owned()));
+        }
+        config.service_ips()?;
+        let validity = [("not_before", &config.not_before), ("not_after", &config.not_after)];
+        for &(name, time) in validity.iter() {
+            if let Some(ref time) = *time {
+                if Asn1Time::from_str(&time).is_err() {
+                    return Err(Error::Config(format!(
+                        "Invalid {} \"{}\", expected time like 20250131235959Z",
+                        name, &time
+                    )));
+                }
+            }
+        }
+        if let (&Some(ref not_before), &Some(ref not_after)) = (&config.not_before, &config.not_after) {
+            let (not_before, not_after) = (Asn1Time::from_str(&not_before)?, Asn1Time::from_str(&not_after)?);
+            if seconds_between(&not_before, &not_after)? <= 0 {
+                return Err(Error::Config("not_after must be later than not_before".to_owned()));
+            }
+        }
+        // not_after sets validity of every leaf certificate, validity_days of entry would be silently ignored
+        if config.not_after.is_some() {
+            let mut entries: Vec<(String, Option<u32>)> = vec![];
+            entries.extend(config.worker.iter().map(|i| (format!("[[worker]] {}", &i.hostname), i.validity_days)));
+            entries.extend(config.etcd_server.iter().map(|i| (format!("[[etcd_server]] {}", &i.hostname), i.validity_days)));
+            let users = config.user.iter().flat_map(|users| users.iter());
+            entries.extend(users.map(|user| (format!("[[user]] {}", &user.username), user.validity_days)));
+            entries.extend(config.components.iter().map(|(name, overrides)| (format!("[components.{}]", &name), overrides.validity_days)));
+            if let Some(&(ref entry, _)) = entries.iter().find(|&&(_, validity_days)| validity_days.is_some()) {
+                return Err(Error::Config(format!(
+                    "validity_days of {} conflicts with not_after, which sets validity of all leaf certificates",
+                    &entry
+                )));
+            }
+        }
+        for component in config.components.keys() {
+            if !COMPONENTS.contains(&component.as_str()) {
+                return Err(Error::Config(format!(
+                    "Unknown component [components.{}], expected one of: {}",
+                    &component,
+                    COMPONENTS.join(", ")
+                )));
+            }
+        }
+
+        if let Some(ref pkcs11) = config.ca.pkcs11 {
+            if pkcs11.key_label.is_empty() {
+                return Err(Error::Config("key_label of [ca.pkcs11] must not be empty".to_owned()));
+            }
+            let ca_names: Vec<&str> = Authority::all().iter().map(|authority| authority.dir_name()).collect();
+            if let Some(ca) = pkcs11.cas.iter().find(|ca| !ca_names.contains(&ca.as_str())) {
+                return Err(Error::Config(format!(
+                    "Unknown CA \"{}\" in cas of [ca.pkcs11], expected one of: {}",
+                    &ca,
+                    ca_names.join(", ")
+                )));
+            }
+            if !pkcs11.cas.is_empty() && config.ca.key_algorithm == KeyAlgorithm::Ed25519 {
+                return Err(Error::Config("Ed25519 CA keys are not supported in PKCS#11 tokens".to_owned()));
+            }
+        }
+
+        Ok(Box::new(config))
+    }
+
+    // Addresses of kubernetes service, one for each service CIDR
+    pub fn service_ips(&self) -> Result<Vec<IpAddr>, Error> {
+        self.service_cidr.iter().map(|cidr| first_ip(&cidr)).collect()
+    }
+}
+
+// First usable address of range, e.g. 10.96.0.1 for 10.96.0.0/12 or fd00::1 for fd00::/108
+fn first_ip(cidr: &str) -> Result<IpAddr, Error> {
+    let invalid = |reason: &str| Error::Config(format!("Invalid service CIDR \"{}\": {}", &cidr, reason));
+    let mut parts = cidr.splitn(2, '/');
+    let addr: IpAddr = match parts.next().unwrap().parse() {
+        Ok(addr) => addr,
+        Err(_) => return Err(invalid("invalid address")),
+    };
+    let prefix: u32 = match parts.next().map(|prefix| prefix.parse()) {
+        Some(Ok(prefix)) => prefix,
+        _ => return Err(invalid("expected <address>/<prefix length>")),
+    };
+    match addr {
+        IpAddr::V4(addr) => {
+            if prefix > 30 {
+                return Err(invalid("prefix length must be at most 30"));
+            }
+            let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+            Ok(IpAddr::V4(Ipv4Addr::from((u32::from(addr) & mask) + 1)))
+        }
+        IpAddr::V6(addr) => {
+            if prefix > 126 {
+                return Err(invalid("prefix length must be at most 126"));
+            }
+            let mask = if prefix == 0 { 0 } else { !0u128 << (128 - prefix) };
+            Ok(IpAddr::V6(Ipv6Addr::from((u128::from(addr) & mask) + 1)))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+fn cert_key_size() -> u32 {
+    2048
+}
+
+fn ca_key_size() -> u32 {
+    4096
+}
+
+fn key_algorithm() -> KeyAlgorithm {
+    KeyAlgorithm::Rsa
+}
+
+fn crl_next_update_days() -> u32 {
+    30
+}
+
+fn pkcs11_cas() -> Vec<String> {
+    vec!["root".to_owned(), "etcd".to_owned(), "front-proxy".to_owned()]
+}
+
+fn generate_true() -> bool {
+    true
+}
+
+fn not_before_skew_seconds() -> u32 {
+    0
+}
+
+fn cap_validity_at_ca() -> bool {
+    true
+}
+
+fn encrypt_keys() -> KeyEncryption {
+    KeyEncryption::None
+}
+
+fn serial_numbers() -> SerialNumbers {
+    SerialNumbers::Random
+}
+
+fn overwrite_false() -> bool {
+    false
+}
+
+fn cluster_domain() -> String {
+    "cluster.local".to_owned()
+}
+
+fn service_cidr() -> Vec<String> {
+    vec!["10.96.0.0/12".to_owned()]
+}
+
+fn out_dir() -> String {
+    "certs".to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn first_ip_of_cidr() {
+        assert_eq!(first_ip("10.96.0.0/12").unwrap(), "10.96.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(first_ip("10.96.12.34/12").unwrap(), "10.96.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(first_ip("192.168.1.0/30").unwrap(), "192.168.1.1".parse::<IpAddr>().unwrap());
+        assert_eq!(first_ip("0.0.0.0/0").unwrap(), "0.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(first_ip("fd00::/108").unwrap(), "fd00::1".parse::<IpAddr>().unwrap());
+        assert_eq!(first_ip("fd00::1234/112").unwrap(), "fd00::1".parse::<IpAddr>().unwrap());
+        assert_eq!(first_ip("::/0").unwrap(), "::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn invalid_cidrs() {
+        for cidr in ["10.96.0.0", "10.96.0/12", "10.96.0.0/x", "10.96.0.0/31", "fd00::/127", "fd00::/129"].iter() {
+            assert!(first_ip(&cidr).is_err(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn overrides_precedence() {
+        let instance = Overrides {
+            validity_days: Some(30),
+            key_size: None,
+            key_algorithm: None,
+        };
+        let component = Overrides {
+            validity_days: Some(180),
+            key_size: Some(4096),
+            key_algorithm: None,
+        };
+        let global = Overrides {
+            validity_days: Some(365),
+            key_size: Some(2048),
+            key_algorithm: Some(KeyAlgorithm::EcdsaP256),
+        };
+        let merged = instance.or(component).or(global);
+        assert_eq!(merged.validity_days, Some(30));
+        assert_eq!(merged.key_size, Some(4096));
+        assert_eq!(merged.key_algorithm, Some(KeyAlgorithm::EcdsaP256));
+
+        let empty = Overrides::default().or(Overrides::default());
+        assert_eq!(empty.validity_days, None);
+        assert_eq!(empty.key_size, None);
+        assert_eq!(empty.key_algorithm, None);
+    }
+
+    #[test]
+    fn user_groups() {
+        let user: User = toml::from_str("username = \"dev\"\ngroup = \"developers\"").unwrap();
+        assert_eq!(user.groups, vec!["developers"]);
+        let user: User = toml::from_str("username = \"dev\"\ngroup = [\"developers\", \"ops\"]").unwrap();
+        assert_eq!(user.groups, vec!["developers", "ops"]);
+        let user: User = toml::from_str("username = \"dev\"").unwrap();
+        assert!(user.groups.is_empty());
+        assert!(toml::from_str::<User>("username = \"dev\"\ngroup = 1").is_err());
+    }
+
+    #[test]
+    fn absolute_validity() {
+        let worker = "[[worker]]\nhostname = \"s2\"\nsan = []\nvalidity_days = 30";
+        let not_after = "not_after = \"20301231235959Z\"";
+        assert!(Config::from_toml(&testing::config(&worker)).is_ok());
+        assert!(Config::from_toml(&testing::config(&not_after)).is_ok());
+        assert!(Config::from_toml(&testing::config(&format!("{}\n{}", &not_after, &worker))).is_err());
+        assert!(Config::from_toml(&testing::config("not_after = \"2030\"")).is_err());
+        let reversed = "not_before = \"20301231235959Z\"\nnot_after = \"20300101000000Z\"";
+        assert!(Config::from_toml(&testing::config(&reversed)).is_err());
+    }
+
+    #[test]
+    fn pkcs11_settings() {
+        let pkcs11 = |settings: &str| {
+            let table = format!("[ca.pkcs11]\nmodule = \"/usr/lib/softhsm/libsofthsm2.so\"\n{}", settings);
+            Config::from_toml(&testing::config(&table))
+        };
+        let config = pkcs11("key_label = \"cm\"").unwrap();
+        let settings = config.ca.pkcs11.as_ref().unwrap();
+        assert_eq!(settings.cas, vec!["root", "etcd", "front-proxy"]);
+        assert!(settings.generate);
+        assert!(pkcs11("key_label = \"cm\"\ncas = [\"etcd\"]\ngenerate = false").is_ok());
+        assert!(pkcs11("key_label = \"\"").is_err());
+        assert!(pkcs11("key_label = \"cm\"\ncas = [\"kubelet\"]").is_err());
+        assert!(pkcs11("").is_err());
+        let ed25519 = testing::config("").replace(
+            "[ca]\nvalidity_days = 100\nkey_algorithm = \"ecdsa-p256\"",
+            "[ca]\nvalidity_days = 100\nkey_algorithm = \"ed25519\"\n[ca.pkcs11]\nmodule = \"m.so\"\nkey_label = \"cm\"",
+        );
+        match Config::from_toml(&ed25519) {
+            Err(Error::Config(message)) => assert!(message.contains("Ed25519")),
+            _ => panic!("Ed25519 CA keys accepted for PKCS#11 token"),
+        }
+    }
+}
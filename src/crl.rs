@@ -0,0 +1,288 @@
+use std::os::raw::{c_char, c_int, c_long, c_ulong, c_void};
+use std::ptr;
+use std::slice;
+use ffi;
+use foreign_types::{ForeignType, ForeignTypeRef};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumRef};
+use openssl::conf::{Conf, ConfMethod};
+use openssl::error::ErrorStack;
+use openssl::x509::extension::AuthorityKeyIdentifier;
+use openssl::x509::X509Builder;
+use database::{Database, RevocationReason, Status};
+use signing_digest;
+use Bundle;
+use Error;
+
+const NID_CRL_NUMBER: c_int = 88;
+const NID_CRL_REASON: c_int = 141;
+const NID_SUBJECT_KEY_IDENTIFIER: c_int = 82;
+
+// CRL functions are not exposed by openssl-sys
+#[allow(non_camel_case_types)]
+enum X509_REVOKED {}
+#[allow(non_camel_case_types)]
+enum ASN1_ENUMERATED {}
+
+extern "C" {
+    fn X509_CRL_new() -> *mut ffi::X509_CRL;
+    fn X509_CRL_free(crl: *mut ffi::X509_CRL);
+    fn X509_CRL_set_version(crl: *mut ffi::X509_CRL, version: c_long) -> c_int;
+    fn X509_CRL_set_issuer_name(crl: *mut ffi::X509_CRL, name: *mut ffi::X509_NAME) -> c_int;
+    fn X509_CRL_set1_lastUpdate(crl: *mut ffi::X509_CRL, tm: *const ffi::ASN1_TIME) -> c_int;
+    fn X509_CRL_set1_nextUpdate(crl: *mut ffi::X509_CRL, tm: *const ffi::ASN1_TIME) -> c_int;
+    fn X509_CRL_add0_revoked(crl: *mut ffi::X509_CRL, revoked: *mut X509_REVOKED) -> c_int;
+    fn X509_CRL_add1_ext_i2d(
+        crl: *mut ffi::X509_CRL,
+        nid: c_int,
+        value: *mut c_void,
+        crit: c_int,
+        flags: c_ulong,
+    ) -> c_int;
+    fn X509_CRL_add_ext(crl: *mut ffi::X509_CRL, ext: *mut ffi::X509_EXTENSION, loc: c_int) -> c_int;
+    fn X509_CRL_sort(crl: *mut ffi::X509_CRL) -> c_int;
+    fn X509_CRL_sign(crl: *mut ffi::X509_CRL, key: *mut ffi::EVP_PKEY, md: *const ffi::EVP_MD) -> c_int;
+    fn PEM_write_bio_X509_CRL(bio: *mut ffi::BIO, crl: *mut ffi::X509_CRL) -> c_int;
+    fn X509_REVOKED_new() -> *mut X509_REVOKED;
+    fn X509_REVOKED_free(revoked: *mut X509_REVOKED);
+    fn X509_REVOKED_set_serialNumber(revoked: *mut X509_REVOKED, serial: *mut ffi::ASN1_INTEGER) -> c_int;
+    fn X509_REVOKED_set_revocationDate(revoked: *mut X509_REVOKED, tm: *mut ffi::ASN1_TIME) -> c_int;
+    fn X509_REVOKED_add1_ext_i2d(
+        revoked: *mut X509_REVOKED,
+        nid: c_int,
+        value: *mut c_void,
+        crit: c_int,
+        flags: c_ulong,
+    ) -> c_int;
+    fn ASN1_ENUMERATED_new() -> *mut ASN1_ENUMERATED;
+    fn ASN1_ENUMERATED_free(value: *mut ASN1_ENUMERATED);
+    fn ASN1_ENUMERATED_set(value: *mut ASN1_ENUMERATED, v: c_long) -> c_int;
+    fn X509_get_ext_by_NID(x: *const ffi::X509, nid: c_int, lastpos: c_int) -> c_int;
+}
+
+fn cvt(result: c_int) -> Result<(), ErrorStack> {
+    match result > 0 {
+        true => Ok(()),
+        false => Err(ErrorStack::get()),
+    }
+}
+
+// Frees CRL if it isn't finished because of error
+struct Crl(*mut ffi::X509_CRL);
+
+impl Drop for Crl {
+    fn drop(&mut self) {
+        unsafe { X509_CRL_free(self.0) }
+    }
+}
+
+// Creates PEM encoded CRL signed by CA with every certificate revoked in CA database
+pub fn gen_crl(
+    ca: &Bundle,
+    database: &Database,
+    crl_number: &BigNumRef,
+    next_update_days: u32,
+) -> Result<Vec<u8>, Error> {
+    let key = ca.private_key()?;
+    let last_update = Asn1Time::days_from_now(0)?;
+    let next_update = Asn1Time::days_from_now(next_update_days)?;
+    let crl_number = crl_number.to_asn1_integer()?;
+
+    unsafe {
+        let crl = X509_CRL_new();
+        if crl.is_null() {
+            return Err(Error::OpenSsl(ErrorStack::get()));
+        }
+        let crl = Crl(crl);
+        // Version 2 because of extensions
+        cvt(X509_CRL_set_version(crl.0, 1))?;
+        cvt(X509_CRL_set_issuer_name(crl.0, ca.cert.subject_name().as_ptr()))?;
+        cvt(X509_CRL_set1_lastUpdate(crl.0, last_update.as_ptr()))?;
+        cvt(X509_CRL_set1_nextUpdate(crl.0, next_update.as_ptr()))?;
+        cvt(X509_CRL_add1_ext_i2d(crl.0, NID_CRL_NUMBER, crl_number.as_ptr() as *mut c_void, 0, 0))?;
+        add_authority_key_id(crl.0, &ca)?;
+
+        for entry in database.entries.iter().filter(|entry| entry.status == Status::Revoked) {
+            let (time, reason) = match entry.revocation()? {
+                Some(revocation) => revocation,
+                None => {
+                    return Err(Error::Index(format!(
+                        "Revoked certificate {} has no revocation date",
+                        &entry.serial
+                    )));
+                }
+            };
+            let serial = BigNum::from_hex_str(&entry.serial)?.to_asn1_integer()?;
+            let time = Asn1Time::from_str(&time)?;
+            add_revoked(crl.0, serial.as_ptr(), time.as_ptr(), reason)?;
+        }
+
+        cvt(X509_CRL_sort(crl.0))?;
+        let digest = signing_digest(&key);
+        cvt(X509_CRL_sign(crl.0, key.as_ptr(), digest.as_ptr()))?;
+        Ok(crl_to_pem(crl.0)?)
+    }
+}
+
+// Authority key identifier is taken from subject key identifier of CA, so CRL of rotated CA
+// can be told apart from CRL of its predecessor with the same name
+unsafe fn add_authority_key_id(crl: *mut ffi::X509_CRL, ca: &Bundle) -> Result<(), ErrorStack> {
+    // CA certificate has no subject key identifier, nothing to refer to
+    if X509_get_ext_by_NID(ca.cert.as_ptr(), NID_SUBJECT_KEY_IDENTIFIER, -1) < 0 {
+        return Ok(());
+    }
+    // Extension context is taken from certificate builder, CRL has no own one
+    let builder = X509Builder::new()?;
+    let conf = Conf::new(ConfMethod::default())?;
+    let mut key_id = AuthorityKeyIdentifier::new();
+    key_id.keyid(true);
+    let extension = key_id.build(&builder.x509v3_context(Some(&ca.cert), Some(&conf)))?;
+    cvt(X509_CRL_add_ext(crl, extension.as_ptr(), -1))
+}
+
+unsafe fn add_revoked(
+    crl: *mut ffi::X509_CRL,
+    serial: *mut ffi::ASN1_INTEGER,
+    time: *mut ffi::ASN1_TIME,
+    reason: RevocationReason,
+) -> Result<(), ErrorStack> {
+    let revoked = X509_REVOKED_new();
+    if revoked.is_null() {
+        return Err(ErrorStack::get());
+    }
+    let mut added = X509_REVOKED_set_serialNumber(revoked, serial) > 0
+        && X509_REVOKED_set_revocationDate(revoked, time) > 0;
+    // Reason code extension should be absent if reason is unspecified
+    if added && reason != RevocationReason::Unspecified {
+        let code = ASN1_ENUMERATED_new();
+        added = !code.is_null()
+            && ASN1_ENUMERATED_set(code, reason.code() as c_long) > 0
+            && X509_REVOKED_add1_ext_i2d(revoked, NID_CRL_REASON, code as *mut c_void, 0, 0) > 0;
+        ASN1_ENUMERATED_free(code);
+    }
+    if !added || X509_CRL_add0_revoked(crl, revoked) <= 0 {
+        X509_REVOKED_free(revoked);
+        return Err(ErrorStack::get());
+    }
+    Ok(())
+}
+
+unsafe fn crl_to_pem(crl: *mut ffi::X509_CRL) -> Result<Vec<u8>, ErrorStack> {
+    let bio = ffi::BIO_new(ffi::BIO_s_mem());
+    if bio.is_null() {
+        return Err(ErrorStack::get());
+    }
+    if PEM_write_bio_X509_CRL(bio, crl) <= 0 {
+        ffi::BIO_free_all(bio);
+        return Err(ErrorStack::get());
+    }
+    let mut data: *mut c_char = ptr::null_mut();
+    let len = ffi::BIO_get_mem_data(bio, &mut data);
+    let pem = slice::from_raw_parts(data as *const u8, len as usize).to_vec();
+    ffi::BIO_free_all(bio);
+    Ok(pem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CertificateParameters;
+    use KeyAlgorithm;
+    use {MemoryStore, Store};
+
+    const NID_AUTHORITY_KEY_IDENTIFIER: c_int = 90;
+
+    extern "C" {
+        fn PEM_read_bio_X509_CRL(
+            bio: *mut ffi::BIO,
+            crl: *mut *mut ffi::X509_CRL,
+            callback: *mut c_void,
+            user: *mut c_void,
+        ) -> *mut ffi::X509_CRL;
+        fn X509_CRL_verify(crl: *mut ffi::X509_CRL, key: *mut ffi::EVP_PKEY) -> c_int;
+        fn X509_CRL_get0_by_serial(
+            crl: *mut ffi::X509_CRL,
+            revoked: *mut *mut X509_REVOKED,
+            serial: *mut ffi::ASN1_INTEGER,
+        ) -> c_int;
+        fn X509_CRL_get_ext_d2i(crl: *const ffi::X509_CRL, nid: c_int, crit: *mut c_int, idx: *mut c_int) -> *mut c_void;
+        fn X509_CRL_get_ext_by_NID(crl: *const ffi::X509_CRL, nid: c_int, lastpos: c_int) -> c_int;
+        fn X509_CRL_get_ext(crl: *const ffi::X509_CRL, loc: c_int) -> *mut ffi::X509_EXTENSION;
+        fn X509_EXTENSION_get_data(ext: *mut ffi::X509_EXTENSION) -> *mut ffi::ASN1_STRING;
+        fn X509_REVOKED_get_ext_d2i(
+            revoked: *const X509_REVOKED,
+            nid: c_int,
+            crit: *mut c_int,
+            idx: *mut c_int,
+        ) -> *mut c_void;
+        fn ASN1_ENUMERATED_get(value: *const ASN1_ENUMERATED) -> c_long;
+    }
+
+    unsafe fn string_bytes(string: *const ffi::ASN1_STRING) -> Vec<u8> {
+        slice::from_raw_parts(ffi::ASN1_STRING_get0_data(string), ffi::ASN1_STRING_length(string) as usize).to_vec()
+    }
+
+    unsafe fn find_revoked(crl: *mut ffi::X509_CRL, serial: u32) -> *mut X509_REVOKED {
+        let serial = BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap();
+        let mut revoked = ptr::null_mut();
+        match X509_CRL_get0_by_serial(crl, &mut revoked, serial.as_ptr()) {
+            1 => revoked,
+            _ => ptr::null_mut(),
+        }
+    }
+
+    #[test]
+    fn revoked_entries() {
+        let mut params = CertificateParameters::ca("test", 2048, 1);
+        params.key_algorithm = KeyAlgorithm::EcdsaP256;
+        let ca = params.gen_cert().unwrap();
+        let store = MemoryStore::new();
+        let index = "R\t301231235959Z\t200101000000Z,keyCompromise\t0C\tcerts/a-0C.crt\t/CN=a\n\
+                     R\t301231235959Z\t200101000000Z\t0D\tcerts/b-0D.crt\t/CN=b\n\
+                     V\t301231235959Z\t\t0E\tcerts/c-0E.crt\t/CN=c\n";
+        store.write("CA/root/index.txt", index.as_bytes()).unwrap();
+        let database = Database::open(&store, "CA/root").unwrap();
+        let pem = gen_crl(&ca, &database, &BigNum::from_u32(7).unwrap(), 30).unwrap();
+
+        unsafe {
+            let bio = ffi::BIO_new_mem_buf(pem.as_ptr() as *mut c_void, pem.len() as c_int);
+            let crl = PEM_read_bio_X509_CRL(bio, ptr::null_mut(), ptr::null_mut(), ptr::null_mut());
+            ffi::BIO_free_all(bio);
+            assert!(!crl.is_null());
+            let crl = Crl(crl);
+            let key = ca.cert.public_key().unwrap();
+            assert_eq!(X509_CRL_verify(crl.0, key.as_ptr()), 1);
+
+            let number = X509_CRL_get_ext_d2i(crl.0, NID_CRL_NUMBER, ptr::null_mut(), ptr::null_mut());
+            assert_eq!(ffi::ASN1_INTEGER_get(number as *const ffi::ASN1_INTEGER), 7);
+            ffi::ASN1_INTEGER_free(number as *mut ffi::ASN1_INTEGER);
+
+            let revoked = find_revoked(crl.0, 0x0c);
+            assert!(!revoked.is_null());
+            let reason = X509_REVOKED_get_ext_d2i(revoked, NID_CRL_REASON, ptr::null_mut(), ptr::null_mut());
+            assert_eq!(ASN1_ENUMERATED_get(reason as *const ASN1_ENUMERATED), 1);
+            ASN1_ENUMERATED_free(reason as *mut ASN1_ENUMERATED);
+            // Unspecified reason has no extension, valid certificate is not listed
+            let revoked = find_revoked(crl.0, 0x0d);
+            assert!(!revoked.is_null());
+            assert!(X509_REVOKED_get_ext_d2i(revoked, NID_CRL_REASON, ptr::null_mut(), ptr::null_mut()).is_null());
+            assert!(find_revoked(crl.0, 0x0e).is_null());
+
+            // keyIdentifier [0] of AuthorityKeyIdentifier sequence is subject key identifier of CA
+            let key_id = ffi::X509_get_ext_d2i(
+                ca.cert.as_ptr(),
+                NID_SUBJECT_KEY_IDENTIFIER,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            ) as *mut ffi::ASN1_STRING;
+            let key_id_bytes = string_bytes(key_id);
+            ffi::ASN1_STRING_free(key_id);
+            let loc = X509_CRL_get_ext_by_NID(crl.0, NID_AUTHORITY_KEY_IDENTIFIER, -1);
+            assert!(loc >= 0);
+            let akid = string_bytes(X509_EXTENSION_get_data(X509_CRL_get_ext(crl.0, loc)));
+            let mut expected = vec![0x30, key_id_bytes.len() as u8 + 2, 0x80, key_id_bytes.len() as u8];
+            expected.extend_from_slice(&key_id_bytes);
+            assert_eq!(akid, expected);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::fmt;
+use ffi;
+use foreign_types::ForeignTypeRef;
+use openssl::asn1::{Asn1StringRef, Asn1Time, Asn1TimeRef};
+use openssl::bn::BigNumRef;
+use openssl::x509::{X509NameRef, X509Ref};
+use Error;
+use Store;
+
+// Certificate status flag of OpenSSL index.txt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Valid,
+    Revoked,
+    Expired,
+}
+
+impl Status {
+    fn flag(&self) -> &'static str {
+        match *self {
+            Status::Valid => "V",
+            Status::Revoked => "R",
+            Status::Expired => "E",
+        }
+    }
+
+    fn from_flag(flag: &str) -> Option<Status> {
+        match flag {
+            "V" => Some(Status::Valid),
+            "R" => Some(Status::Revoked),
+            "E" => Some(Status::Expired),
+            _ => None,
+        }
+    }
+}
+
+// CRL reason codes in the form they are written to index.txt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RevocationReason {
+    Unspecified,
+    KeyCompromise,
+    CaCompromise,
+    AffiliationChanged,
+    Superseded,
+    CessationOfOperation,
+    CertificateHold,
+}
+
+impl RevocationReason {
+    pub fn all() -> Vec<RevocationReason> {
+        vec![
+            RevocationReason::Unspecified,
+            RevocationReason::KeyCompromise,
+            RevocationReason::CaCompromise,
+            RevocationReason::AffiliationChanged,
+            RevocationReason::Superseded,
+            RevocationReason::CessationOfOperation,
+            RevocationReason::CertificateHold,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RevocationReason::Unspecified => "unspecified",
+            RevocationReason::KeyCompromise => "keyCompromise",
+            RevocationReason::CaCompromise => "CACompromise",
+            RevocationReason::AffiliationChanged => "affiliationChanged",
+            RevocationReason::Superseded => "superseded",
+            RevocationReason::CessationOfOperation => "cessationOfOperation",
+            RevocationReason::CertificateHold => "certificateHold",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RevocationReason> {
+        RevocationReason::all().into_iter().find(|reason| reason.name() == name)
+    }
+
+    // Value of reasonCode CRL entry extension
+    pub fn code(&self) -> i64 {
+        match *self {
+            RevocationReason::Unspecified => 0,
+            RevocationReason::KeyCompromise => 1,
+            RevocationReason::CaCompromise => 2,
+            RevocationReason::AffiliationChanged => 3,
+            RevocationReason::Superseded => 4,
+            RevocationReason::CessationOfOperation => 5,
+            RevocationReason::CertificateHold => 6,
+        }
+    }
+}
+
+// Line of OpenSSL index.txt
+pub struct Entry {
+    pub status: Status,
+    // Expiry time as written in certificate, e.g. "301231235959Z"
+    pub expiry: String,
+    // Revocation time and optional reason: "<time>[,<reason>]"
+    pub revocation: Option<String>,
+    // Serial number in upper case hex
+    pub serial: String,
+    // Certificate file relative to CA directory
+    pub filename: String,
+    // Subject in "/C=../O=../CN=.." form
+    pub subject: String,
+}
+
+impl Entry {
+    pub fn from_cert(cert: &X509Ref, filename: &str) -> Result<Entry, Error> {
+        let serial = cert.serial_number().to_bn()?;
+        Ok(Entry {
+            status: Status::Valid,
+            expiry: asn1_time_string(cert.not_after()),
+            revocation: None,
+            serial: serial_hex(&serial)?,
+            filename: filename.to_owned(),
+            subject: subject_dn(cert.subject_name())?,
+        })
+    }
+
+    pub fn has_common_name(&self, common_name: &str) -> bool {
+        let field = format!("CN={}", &common_name);
+        self.subject.split('/').any(|value| value == field)
+    }
+
+    fn parse(line: &str) -> Option<Entry> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 6 {
+            return None;
+        }
+        Some(Entry {
+            status: Status::from_flag(fields[0])?,
+            expiry: fields[1].to_owned(),
+            revocation: match fields[2] {
+                "" => None,
+                revocation => Some(revocation.to_owned()),
+            },
+            serial: fields[3].to_owned(),
+            filename: fields[4].to_owned(),
+            subject: fields[5].to_owned(),
+        })
+    }
+
+    // Revocation time and reason of revoked certificate
+    pub fn revocation(&self) -> Result<Option<(String, RevocationReason)>, Error> {
+        let revocation = match self.revocation {
+            Some(ref revocation) => revocation,
+            None => return Ok(None),
+        };
+        let mut fields = revocation.splitn(2, ',');
+        let time = fields.next().unwrap_or("").to_owned();
+        let reason = match fields.next() {
+            Some(name) => match RevocationReason::from_name(&name) {
+                Some(reason) => reason,
+                None => {
+                    return Err(Error::Index(format!(
+                        "Unknown revocation reason of certificate {}: {}",
+                        &self.serial, name
+                    )));
+                }
+            },
+            None => RevocationReason::Unspecified,
+        };
+        Ok(Some((time, reason)))
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let revocation = match self.revocation {
+            Some(ref revocation) => revocation,
+            None => "",
+        };
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.status.flag(),
+            self.expiry,
+            revocation,
+            self.serial,
+            self.filename,
+            self.subject
+        )
+    }
+}
+
+// Issuance of short-lived certificate recorded in "index.txt.audit"
+pub struct AuditRecord {
+    pub time: String,
+    pub serial: String,
+    pub requester: String,
+    pub ttl: i64,
+}
+
+// Certificate database of CA, readable by "openssl ca"
+pub struct Database {
+    filename: String,
+    pub entries: Vec<Entry>,
+}
+
+impl Database {
+    // Reads "<ca_dir>/index.txt", missing file means empty database
+    pub fn open(store: &dyn Store, ca_dir: &str) -> Result<Database, Error> {
+        let filename = format!("{}/index.txt", &ca_dir);
+        let mut entries = Vec::new();
+        if store.exists(&filename) {
+            let contents = String::from_utf8_lossy(&store.read(&filename)?).into_owned();
+            for (n, line) in contents.lines().enumerate() {
+                if line.is_empty() {
+                    continue;
+                }
+                match Entry::parse(&line) {
+                    Some(entry) => entries.push(entry),
+                    None => {
+                        return Err(Error::Index(format!(
+                            "Malformed line {} in {}",
+                            n + 1,
+                            &filename
+                        )));
+                    }
+                }
+            }
+        }
+        Ok(Database { filename, entries })
+    }
+
+    pub fn append(&mut self, store: &dyn Store, entry: Entry) -> Result<(), Error> {
+        // Same subject is issued again on renewal
+        let attr_filename = format!("{}.attr", &self.filename);
+        if !store.exists(&attr_filename) {
+            store.write(&attr_filename, b"unique_subject = no\n")?;
+        }
+        self.entries.push(entry);
+        self.save(store)
+    }
+
+    pub fn save(&self, store: &dyn Store) -> Result<(), Error> {
+        let mut contents = String::new();
+        for entry in self.entries.iter() {
+            contents.push_str(&format!("{}\n", entry));
+        }
+        store.write(&self.filename, contents.as_bytes())
+    }
+
+    // Appends "<time>\t<serial>\t<requester>\t<ttl>s\t<subject>" to "index.txt.audit",
+    // so it can be found out who was given short-lived access and when
+    pub fn audit(&self, store: &dyn Store, serial: &BigNumRef, requester: &str, ttl: i64) -> Result<(), Error> {
+        let entry = match self.find(&serial)? {
+            Some(entry) => entry,
+            None => {
+                return Err(Error::Index(format!(
+                    "Certificate {} not found in {}",
+                    serial_hex(&serial)?,
+                    &self.filename
+                )));
+            }
+        };
+        let audit_filename = self.audit_filename();
+        let mut contents = match store.exists(&audit_filename) {
+            true => store.read(&audit_filename)?,
+            false => vec![],
+        };
+        let now = Asn1Time::days_from_now(0)?;
+        let line = format!(
+            "{}\t{}\t{}\t{}s\t{}\n",
+            asn1_time_string(&now),
+            &entry.serial,
+            &requester,
+            ttl,
+            &entry.subject
+        );
+        contents.extend_from_slice(line.as_bytes());
+        store.write(&audit_filename, &contents)
+    }
+
+    // Reads records written by audit, missing file means no short-lived certificates were issued
+    pub fn audit_records(&self, store: &dyn Store) -> Result<Vec<AuditRecord>, Error> {
+        let audit_filename = self.audit_filename();
+        let mut records = vec![];
+        if !store.exists(&audit_filename) {
+            return Ok(records);
+        }
+        let contents = String::from_utf8_lossy(&store.read(&audit_filename)?).into_owned();
+        for (n, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let ttl = fields.get(3).and_then(|ttl| ttl.trim_end_matches('s').parse().ok());
+            match ttl {
+                Some(ttl) if fields.len() == 5 => records.push(AuditRecord {
+                    time: fields[0].to_owned(),
+                    serial: fields[1].to_owned(),
+                    requester: fields[2].to_owned(),
+                    ttl,
+                }),
+                _ => {
+                    return Err(Error::Index(format!(
+                        "Malformed line {} in {}",
+                        n + 1,
+                        &audit_filename
+                    )));
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    fn audit_filename(&self) -> String {
+        format!("{}.audit", &self.filename)
+    }
+
+    pub fn find(&self, serial: &BigNumRef) -> Result<Option<&Entry>, Error> {
+        let serial = serial_hex(&serial)?;
+        Ok(self.entries.iter().find(|entry| entry.serial == serial))
+    }
+
+    // Marks certificate as revoked and writes database
+    pub fn revoke(&mut self, store: &dyn Store, serial: &BigNumRef, reason: RevocationReason) -> Result<(), Error> {
+        let serial = serial_hex(&serial)?;
+        let now = Asn1Time::days_from_now(0)?;
+        match self.entries.iter_mut().find(|entry| entry.serial == serial) {
+            Some(entry) => {
+                if entry.status == Status::Revoked {
+                    return Err(Error::Policy(format!("Certificate {} is already revoked", &serial)));
+                }
+                entry.status = Status::Revoked;
+                entry.revocation = Some(format!("{},{}", asn1_time_string(&now), reason.name()));
+            }
+            None => {
+                return Err(Error::Index(format!(
+                    "Certificate {} not found in {}",
+                    &serial, &self.filename
+                )));
+            }
+        }
+        self.save(store)
+    }
+}
+
+// Serial number in form used by OpenSSL: upper case hex with even number of digits
+pub fn serial_hex(serial: &BigNumRef) -> Result<String, Error> {
+    let hex = serial.to_hex_str()?.to_string();
+    match hex.len() % 2 {
+        0 => Ok(hex),
+        _ => Ok(format!("0{}", hex)),
+    }
+}
+
+// ASN1_TIME is ASN1_STRING holding UTCTime or GeneralizedTime
+fn asn1_time_string(time: &Asn1TimeRef) -> String {
+    let string = unsafe { Asn1StringRef::from_ptr(time.as_ptr() as *mut ffi::ASN1_STRING) };
+    String::from_utf8_lossy(string.as_slice()).into_owned()
+}
+
+pub fn subject_dn(name: &X509NameRef) -> Result<String, Error> {
+    let mut dn = String::new();
+    for entry in name.entries() {
+        let field = entry.object().nid().short_name()?;
+        let value = String::from_utf8_lossy(entry.data().as_slice());
+        dn.push_str(&format!("/{}={}", field, value));
+    }
+    Ok(dn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNum;
+    use MemoryStore;
+
+    #[test]
+    fn entry_round_trip() {
+        let lines = [
+            "V\t301231235959Z\t\t0A1B\tcerts/admin-0A1B.crt\t/O=system:masters/CN=admin",
+            "R\t301231235959Z\t200101000000Z,superseded\t0C\tcerts/kubelet-node1-0C.crt\t/CN=system:node:node1",
+            "E\t20500101000000Z\t\tFF\tcerts/ca.crt\t/CN=kubernetes",
+        ];
+        for line in lines.iter() {
+            let entry = Entry::parse(&line).unwrap();
+            assert_eq!(entry.to_string(), *line);
+        }
+    }
+
+    #[test]
+    fn entry_fields() {
+        let entry = Entry::parse("R\t301231235959Z\t200101000000Z,keyCompromise\t0C\tcerts/a.crt\t/O=g/CN=a").unwrap();
+        assert_eq!(entry.status, Status::Revoked);
+        assert_eq!(entry.serial, "0C");
+        assert_eq!(entry.filename, "certs/a.crt");
+        assert_eq!(
+            entry.revocation().unwrap(),
+            Some(("200101000000Z".to_owned(), RevocationReason::KeyCompromise))
+        );
+        assert!(entry.has_common_name("a"));
+        assert!(!entry.has_common_name("g"));
+    }
+
+    #[test]
+    fn malformed_entries() {
+        assert!(Entry::parse("V\t301231235959Z\t\t0A\tcerts/a.crt").is_none());
+        assert!(Entry::parse("X\t301231235959Z\t\t0A\tcerts/a.crt\t/CN=a").is_none());
+        let entry = Entry::parse("R\t301231235959Z\t200101000000Z,bogus\t0A\tcerts/a.crt\t/CN=a").unwrap();
+        assert!(entry.revocation().is_err());
+    }
+
+    #[test]
+    fn serial_hex_is_padded() {
+        assert_eq!(serial_hex(&BigNum::from_u32(0).unwrap()).unwrap(), "00");
+        assert_eq!(serial_hex(&BigNum::from_u32(0xabc).unwrap()).unwrap(), "0ABC");
+        assert_eq!(serial_hex(&BigNum::from_u32(0xabcd).unwrap()).unwrap(), "ABCD");
+    }
+
+    #[test]
+    fn audit_records() {
+        let store = MemoryStore::new();
+        let mut database = Database::open(&store, "CA/root").unwrap();
+        let entry = Entry::parse("V\t301231235959Z\t\t1F\tcerts/user-dev-1F.crt\t/O=developers/CN=dev").unwrap();
+        database.append(&store, entry).unwrap();
+        assert!(database.audit_records(&store).unwrap().is_empty());
+
+        database.audit(&store, &BigNum::from_u32(0x1f).unwrap(), "alice", 28800).unwrap();
+        let records = database.audit_records(&store).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].serial, "1F");
+        assert_eq!(records[0].requester, "alice");
+        assert_eq!(records[0].ttl, 28800);
+
+        store.write("CA/root/index.txt.audit", b"20300101000000Z\t1F\talice\n").unwrap();
+        assert!(database.audit_records(&store).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use database::subject_dn;
+use foreign_types::ForeignTypeRef;
+use kubernetes::Profile;
+use openssl::error::ErrorStack;
+use openssl::x509::X509Ref;
+use std::os::raw::c_uint;
+use {AltName, Error};
+
+// Returned when certificate has no such extension
+const NO_EXTENSION: c_uint = c_uint::max_value();
+
+const KEY_USAGES: &[(c_uint, &str)] = &[
+    (0x0080, "digital_signature"),
+    (0x0040, "non_repudiation"),
+    (0x0020, "key_encipherment"),
+    (0x0010, "data_encipherment"),
+    (0x0008, "key_agreement"),
+    (0x0004, "key_cert_sign"),
+    (0x0002, "crl_sign"),
+    (0x0001, "encipher_only"),
+    (0x8000, "decipher_only"),
+];
+
+const EXTENDED_KEY_USAGES: &[(c_uint, &str)] = &[
+    (0x0001, "server_auth"),
+    (0x0002, "client_auth"),
+    (0x0008, "code_signing"),
+    (0x0040, "time_stamping"),
+];
+
+// Not exposed by openssl-sys
+extern "C" {
+    fn X509_get_key_usage(x: *mut ::ffi::X509) -> c_uint;
+    fn X509_get_extended_key_usage(x: *mut ::ffi::X509) -> c_uint;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    Subject,
+    San,
+    KeyUsage,
+    ExtendedKeyUsage,
+    Issuer,
+}
+
+impl Field {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Field::Subject => "subject",
+            Field::San => "SAN",
+            Field::KeyUsage => "key usage",
+            Field::ExtendedKeyUsage => "extended key usage",
+            Field::Issuer => "issuer",
+        }
+    }
+}
+
+// Field of issued certificate which differs from config
+pub struct Mismatch {
+    pub field: Field,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl<'a> Profile<'a> {
+    // Compares certificate with parameters derived from current config.
+    // Issuer is checked only if certificate of CA is given.
+    pub fn mismatches(&self, cert: &X509Ref, ca_cert: Option<&X509Ref>) -> Result<Vec<Mismatch>, Error> {
+        let params = self.params();
+        let mut mismatches = vec![];
+        let mut check = |field: Field, expected: String, actual: String| {
+            if expected != actual {
+                mismatches.push(Mismatch {
+                    field,
+                    expected,
+                    actual,
+                });
+            }
+        };
+
+        let subject = params.subject_name()?;
+        check(Field::Subject, subject_dn(&subject)?, subject_dn(cert.subject_name())?);
+
+        let mut san = vec![];
+        if let Some(ref entries) = params.san {
+            for entry in entries.iter() {
+                san.push(AltName::parse(&entry)?.to_string());
+            }
+        }
+        let cert_san = AltName::from_cert(&cert).iter().map(|name| name.to_string()).collect();
+        check(Field::San, sorted(san), sorted(cert_san));
+
+        let key_usage = params.key_usage_for(cert.public_key()?.id());
+        let key_usage = key_usage.iter().filter(|&&usage| usage != "critical").map(|&usage| usage.to_owned());
+        let cert_key_usage = usages(unsafe { X509_get_key_usage(cert.as_ptr()) }, KEY_USAGES);
+        check(Field::KeyUsage, sorted(key_usage.collect()), sorted(cert_key_usage));
+
+        let extended_key_usage = match params.extended_key_usage {
+            Some(ref usages) => usages.iter().filter(|&&usage| usage != "critical").map(|&usage| usage.to_owned()).collect(),
+            None => vec![],
+        };
+        let cert_extended_key_usage = usages(unsafe { X509_get_extended_key_usage(cert.as_ptr()) }, EXTENDED_KEY_USAGES);
+        check(Field::ExtendedKeyUsage, sorted(extended_key_usage), sorted(cert_extended_key_usage));
+
+        if let Some(ca_cert) = ca_cert {
+            let ca_subject = subject_dn(ca_cert.subject_name())?;
+            let mut issuer = subject_dn(cert.issuer_name())?;
+            // Successor of rotated CA has the same subject, only signature tells which one issued cert.
+            // Verification fails with error instead of false if key types differ.
+            if issuer == ca_subject && !cert.verify(&*ca_cert.public_key()?).unwrap_or(false) {
+                let _ = ErrorStack::get(); // clear queued verify error
+                issuer = format!("{} (signed by other key)", &issuer);
+            }
+            check(Field::Issuer, ca_subject, issuer);
+        }
+        Ok(mismatches)
+    }
+}
+
+fn usages(flags: c_uint, names: &[(c_uint, &str)]) -> Vec<String> {
+    if flags == NO_EXTENSION {
+        return vec![];
+    }
+    names.iter().filter(|&&(flag, _)| flags & flag != 0).map(|&(_, name)| name.to_owned()).collect()
+}
+
+fn sorted(mut values: Vec<String>) -> String {
+    values.sort();
+    values.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kubernetes::{profile, Authority, CertType, CA};
+    use openssl::x509::X509;
+    use testing;
+    use {Bundle, Config};
+
+    fn fields(mismatches: &[Mismatch]) -> Vec<Field> {
+        mismatches.iter().map(|mismatch| mismatch.field).collect()
+    }
+
+    #[test]
+    fn profile_mismatches() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let ca = CA::generate(&config).unwrap();
+        let root = ca.bundle(Authority::Root);
+        let cert_type = CertType::KubeletServer(&config.worker[0]);
+        let server = profile(&config, &cert_type);
+        let issue = |ca: &Box<Bundle>, key_usage: Option<Vec<&str>>| -> X509 {
+            let mut params = server.params();
+            params.ca = Some(ca);
+            if let Some(key_usage) = key_usage {
+                params.key_usage = key_usage;
+                params.extended_key_usage = Some(vec!["client_auth"]);
+            }
+            params.gen_cert().unwrap().cert
+        };
+
+        let cert = issue(&root, None);
+        assert!(server.mismatches(&cert, Some(&root.cert)).unwrap().is_empty());
+        assert!(server.matches(&cert).unwrap());
+
+        let changed = testing::config("").replace("\"10.0.22.2\", \"s1\"", "\"10.0.22.4\", \"s1\"");
+        let changed = Config::from_toml(&changed).unwrap();
+        let changed_type = CertType::KubeletServer(&changed.worker[0]);
+        let changed_server = profile(&changed, &changed_type);
+        let mismatches = changed_server.mismatches(&cert, Some(&root.cert)).unwrap();
+        assert_eq!(fields(&mismatches), vec![Field::San]);
+        assert!(mismatches[0].expected.contains("10.0.22.4"));
+        assert!(mismatches[0].actual.contains("10.0.22.2"));
+        assert!(!changed_server.matches(&cert).unwrap());
+
+        let cert = issue(&root, Some(vec!["digital_signature", "non_repudiation"]));
+        let mismatches = server.mismatches(&cert, None).unwrap();
+        assert_eq!(fields(&mismatches), vec![Field::KeyUsage, Field::ExtendedKeyUsage]);
+        assert_eq!(mismatches[1].actual, "client_auth");
+        assert!(server.matches(&cert).unwrap());
+
+        // Successor of rotated CA has the same subject but other key
+        let other = CA::generate(&config).unwrap();
+        let cert = issue(other.bundle(Authority::Root), None);
+        let mismatches = server.mismatches(&cert, Some(&root.cert)).unwrap();
+        assert_eq!(fields(&mismatches), vec![Field::Issuer]);
+        assert_eq!(mismatches[0].actual, format!("{} (signed by other key)", &mismatches[0].expected));
+        assert!(ErrorStack::get().errors().is_empty());
+
+        let cert = issue(ca.bundle(Authority::Etcd), None);
+        let mismatches = server.mismatches(&cert, Some(&root.cert)).unwrap();
+        assert_eq!(fields(&mismatches), vec![Field::Issuer]);
+        assert!(!mismatches[0].actual.ends_with("(signed by other key)"));
+    }
+}
@@ -0,0 +1,47 @@
+use std::error;
+use std::fmt;
+use std::io;
+use openssl::error::ErrorStack;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    OpenSsl(ErrorStack),
+    Config(String),
+    Index(String),
+    Policy(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::OpenSsl(ref err) => write!(f, "OpenSSL error: {}", err),
+            Error::Config(ref msg) => write!(f, "Config error: {}", msg),
+            Error::Index(ref msg) => write!(f, "Index error: {}", msg),
+            Error::Policy(ref msg) => write!(f, "Policy error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::OpenSsl(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<ErrorStack> for Error {
+    fn from(err: ErrorStack) -> Error {
+        Error::OpenSsl(err)
+    }
+}
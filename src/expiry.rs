@@ -0,0 +1,202 @@
+extern crate base64;
+extern crate serde_yaml;
+
+use cert_machine::{seconds_until, serial_hex, Authority, Config, Error, Store};
+use inventory::inventory;
+use openssl::error::ErrorStack;
+use openssl::x509::X509;
+
+const OK: i32 = 0;
+const WARNING: i32 = 1;
+const CRITICAL: i32 = 2;
+
+struct ActiveCert {
+    ca: String,
+    // Where certificate is used: symlink, kubeconfig or CA certificate file
+    name: String,
+    serial: String,
+    seconds_remaining: i64,
+}
+
+// Checks expiry of every certificate in use.
+// Returns 0 if all are fine, 1 if some expire within warn seconds, 2 if within critical seconds.
+pub fn check_expiry(store: &dyn Store, config: &Config, warn: i64, critical: i64, textfile: Option<&str>) -> Result<i32, Error> {
+    let certs = active_certs(store, &config)?;
+    let mut status = OK;
+    for cert in certs.iter() {
+        let cert_status = match cert.seconds_remaining {
+            seconds if seconds < critical => CRITICAL,
+            seconds if seconds < warn => WARNING,
+            _ => OK,
+        };
+        let label = match cert_status {
+            CRITICAL => "CRITICAL",
+            WARNING => "WARNING",
+            _ => "OK",
+        };
+        let days = cert.seconds_remaining / 86400;
+        match cert.seconds_remaining < 0 {
+            true => println!("{} {} ({} CA, serial {}) expired {} days ago", label, &cert.name, &cert.ca, &cert.serial, -days),
+            false => println!("{} {} ({} CA, serial {}) expires in {} days", label, &cert.name, &cert.ca, &cert.serial, days),
+        }
+        if cert_status > status {
+            status = cert_status;
+        }
+    }
+    if let Some(textfile) = textfile {
+        write_textfile(store, &certs, &textfile)?;
+    }
+    Ok(status)
+}
+
+// CA certificates, certificates with symlinks pointing at them and certificates embedded in kubeconfigs.
+// Revoked and superseded certificates are not in use.
+fn active_certs(store: &dyn Store, config: &Config) -> Result<Vec<ActiveCert>, Error> {
+    let mut certs = vec![];
+    for info in inventory(store, &config)? {
+        if info.status == "revoked" {
+            continue;
+        }
+        let ca_filename = format!("{}/CA/{}/certs/ca.crt", &config.out_dir, &info.ca);
+        let name = match info.symlinks.first() {
+            Some(symlink) => symlink.clone(),
+            None if info.filename == ca_filename => format!("CA/{}/certs/ca.crt", &info.ca),
+            None => continue,
+        };
+        certs.push(ActiveCert {
+            ca: info.ca,
+            name,
+            serial: info.serial,
+            seconds_remaining: info.seconds_remaining,
+        });
+    }
+
+    let mut ca_certs = vec![];
+    for authority in Authority::all() {
+        let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+        if store.exists(&format!("{}/certs/ca.crt", &ca_dir)) {
+            ca_certs.push((authority, store.read_cert(&ca_dir, "ca")?));
+        }
+    }
+    // Kubeconfigs linked from other directories are counted once
+    let kubeconfigs = store.list(&config.out_dir)?.into_iter().filter(|name| name.ends_with(".kubeconfig"));
+    for name in kubeconfigs {
+        let kubeconfig = format!("{}/{}", &config.out_dir, &name);
+        if store.is_link(&kubeconfig) {
+            continue;
+        }
+        for cert in kubeconfig_certs(store, &kubeconfig)? {
+            let mut ca = "unknown";
+            for &(authority, ref ca_cert) in ca_certs.iter() {
+                let ca_public_key = ca_cert.cert.public_key()?;
+                // Verification fails with error instead of false if key types differ,
+                // e.g. after CA was rotated to other key algorithm
+                match cert.verify(&ca_public_key) {
+                    Ok(true) => ca = authority.dir_name(),
+                    _ => {
+                        // Drop errors failed verification left in queue
+                        let _ = ErrorStack::get();
+                    }
+                }
+            }
+            let serial = cert.serial_number().to_bn()?;
+            certs.push(ActiveCert {
+                ca: ca.to_owned(),
+                name: name.clone(),
+                serial: serial_hex(&serial)?,
+                seconds_remaining: seconds_until(cert.not_after())?,
+            });
+        }
+    }
+    Ok(certs)
+}
+
+// Client certificates embedded in kubeconfig
+fn kubeconfig_certs(store: &dyn Store, filename: &str) -> Result<Vec<X509>, Error> {
+    let contents = String::from_utf8_lossy(&store.read(&filename)?).into_owned();
+    let kubeconfig: serde_yaml::Value = match serde_yaml::from_str(&contents) {
+        Ok(kubeconfig) => kubeconfig,
+        Err(err) => return Err(Error::Config(format!("Unable to parse kubeconfig {}: {}", &filename, err))),
+    };
+    let mut certs = vec![];
+    if let Some(users) = kubeconfig["users"].as_sequence() {
+        for user in users {
+            if let Some(data) = user["user"]["client-certificate-data"].as_str() {
+                let pem = match base64::decode(&data) {
+                    Ok(pem) => pem,
+                    Err(err) => {
+                        return Err(Error::Config(format!(
+                            "Unable to decode client certificate in {}: {}",
+                            &filename, err
+                        )));
+                    }
+                };
+                certs.push(X509::from_pem(&pem)?);
+            }
+        }
+    }
+    Ok(certs)
+}
+
+// Writes metrics in node_exporter textfile collector format.
+// File is replaced atomically so collector never reads partial output.
+fn write_textfile(store: &dyn Store, certs: &[ActiveCert], filename: &str) -> Result<(), Error> {
+    let mut metrics = String::new();
+    metrics.push_str("# HELP cert_machine_cert_expiry_seconds Seconds until certificate expires.\n");
+    metrics.push_str("# TYPE cert_machine_cert_expiry_seconds gauge\n");
+    for cert in certs {
+        metrics.push_str(&format!(
+            "cert_machine_cert_expiry_seconds{{ca=\"{}\",name=\"{}\",serial=\"{}\"}} {}\n",
+            label_value(&cert.ca),
+            label_value(&cert.name),
+            label_value(&cert.serial),
+            cert.seconds_remaining
+        ));
+    }
+    store.write_atomic(&filename, metrics.as_bytes())
+}
+
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::{all_cert_types, issue_profile, profile, CertType, MemoryStore};
+    use create_ca;
+    use testing;
+
+    #[test]
+    fn near_expiry() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let store = MemoryStore::new();
+        let ca = create_ca(&store, &config).unwrap();
+        for cert_type in all_cert_types(&config) {
+            let mut profile = profile(&config, &cert_type);
+            match cert_type {
+                CertType::ApiServer => profile.validity_seconds = Some(3600),
+                CertType::Admin => profile.validity_seconds = Some(2 * 86400),
+                _ => (),
+            }
+            issue_profile(&store, &ca, &profile, None, None).unwrap();
+        }
+
+        assert_eq!(check_expiry(&store, &config, 7 * 86400, 7200, None).unwrap(), CRITICAL);
+        assert_eq!(check_expiry(&store, &config, 7 * 86400, 60, None).unwrap(), WARNING);
+        assert_eq!(check_expiry(&store, &config, 60, 60, None).unwrap(), OK);
+
+        check_expiry(&store, &config, 7 * 86400, 7200, Some("metrics/certs.prom")).unwrap();
+        let metrics = String::from_utf8(store.read("metrics/certs.prom").unwrap()).unwrap();
+        let seconds = |name: &str| -> i64 {
+            let line = metrics.lines().find(|line| line.contains(&format!("name=\"{}\"", name))).unwrap();
+            line.rsplit(' ').next().unwrap().parse().unwrap()
+        };
+        let apiserver = seconds("master/apiserver.crt");
+        assert!(apiserver > 3500 && apiserver <= 3600);
+        let admin = seconds("users/admin.kubeconfig");
+        assert!(admin > 2 * 86400 - 100 && admin <= 2 * 86400);
+        assert!(seconds("master/ca.crt") > 99 * 86400);
+        assert!(metrics.contains("ca=\"root\",name=\"users/admin.kubeconfig\""));
+    }
+}
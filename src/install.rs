@@ -0,0 +1,280 @@
+use config::{Config, SerialNumbers};
+use kubernetes::{profile, Authority, CertType, Profile, CA};
+use openssl::bn::{BigNum, BigNumRef};
+use openssl::x509::X509ReqRef;
+use std::path::Path;
+use {Bundle, Database, Entry, Error, Status, Store};
+
+// Writes bundle into CA directory as "<filename>-<sn>" ("ca" is kept as is) and records it in database
+pub fn store_bundle(
+    store: &dyn Store,
+    bundle: &Bundle,
+    out_dir: &str,
+    filename: &str,
+    overwrite: bool,
+) -> Result<(), Error> {
+    let sn = bundle.cert.serial_number().to_bn()?;
+    let name = match filename {
+        "ca" => filename.to_owned(),
+        _ => format!("{}-{}", &filename, sn),
+    };
+    let crt_filename = format!("{}/certs/{}.crt", &out_dir, &name);
+    let key_filename = format!("{}/keys/{}.key", &out_dir, &name);
+
+    // Private key of certificate issued for signing request is kept by requester
+    let has_key = !bundle.key.is_empty();
+    // Nothing is written unless both files can be, so every stored certificate is in database
+    let mut existing = vec![];
+    if store.exists(&crt_filename) {
+        existing.push(&crt_filename);
+    }
+    if has_key && store.exists(&key_filename) {
+        existing.push(&key_filename);
+    }
+    for filename in existing.iter() {
+        match overwrite {
+            true => println!("OVERWRITING: {}", &filename),
+            false => eprintln!("File exists: {}!", &filename),
+        }
+    }
+    if !existing.is_empty() && !overwrite {
+        return Ok(());
+    }
+    store.write(&crt_filename, &bundle.to_pem()?)?;
+    if has_key {
+        store.write(&key_filename, &bundle.key)?;
+    }
+    let mut database = Database::open(store, &out_dir)?;
+    database.append(store, Entry::from_cert(&bundle.cert, &format!("certs/{}.crt", &name))?)
+}
+
+// Serial number for next certificate of CA, None means random one
+fn next_sn(store: &dyn Store, config: &Config, ca_dir: &str) -> Result<Option<BigNum>, Error> {
+    match config.serial_numbers {
+        SerialNumbers::Random => Ok(None),
+        SerialNumbers::Counter => {
+            let index_filename = format!("{}/index", &ca_dir);
+            Ok(Some(BigNum::from_u32(get_sn(store, &index_filename)? + 1)?))
+        }
+    }
+}
+
+fn get_sn(store: &dyn Store, filename: &str) -> Result<u32, Error> {
+    if !store.exists(&filename) {
+        store.write(&filename, b"0")?;
+        return Ok(0);
+    }
+    let contents = String::from_utf8_lossy(&store.read(&filename)?).into_owned();
+    match contents.trim().parse() {
+        Ok(sn) => Ok(sn),
+        Err(err) => Err(Error::Index(format!(
+            "Unable to read index from index file {}: {}",
+            &filename, err
+        ))),
+    }
+}
+
+fn write_sn(store: &dyn Store, filename: &str, sn: &BigNumRef) -> Result<(), Error> {
+    store.write(&filename, sn.to_string().as_bytes())
+}
+
+// Certificates are stored as "certs/<filename>-<sn>.crt"
+pub fn is_cert_of(cert_filename: &str, filename: &str) -> bool {
+    let prefix = format!("certs/{}-", &filename);
+    if !cert_filename.starts_with(&prefix) || !cert_filename.ends_with(".crt") {
+        return false;
+    }
+    let sn = &cert_filename[prefix.len()..cert_filename.len() - ".crt".len()];
+    !sn.is_empty() && sn.chars().all(|c| c.is_ascii_digit())
+}
+
+// Latest valid certificate issued for profile, with its key if CA directory has it.
+// Kubelet client and server certificates share file name, they differ by common name.
+pub fn current_cert(store: &dyn Store, profile: &Profile) -> Result<Option<Box<Bundle>>, Error> {
+    let ca_dir = profile.ca_dir();
+    let database = Database::open(store, &ca_dir)?;
+    let entry = database
+        .entries
+        .iter()
+        .rev()
+        .filter(|entry| entry.status == Status::Valid)
+        .filter(|entry| is_cert_of(&entry.filename, &profile.filename))
+        .find(|entry| entry.has_common_name(&profile.common_name));
+    let name = match entry {
+        Some(entry) => entry.filename["certs/".len()..entry.filename.len() - ".crt".len()].to_owned(),
+        None => return Ok(None),
+    };
+    match store.exists(&format!("{}/keys/{}.key", &ca_dir, &name)) {
+        true => Ok(Some(store.read_bundle(&ca_dir, &name)?)),
+        false => Ok(Some(store.read_cert(&ca_dir, &name)?)),
+    }
+}
+
+// Private key currently in use: taken through key symlink of profile if it has one,
+// otherwise key of latest issued certificate
+pub fn current_key(store: &dyn Store, profile: &Profile) -> Result<Option<Vec<u8>>, Error> {
+    if let Some(ref symlink_path) = profile.symlink {
+        let key_filename = format!("{}.key", &symlink_path);
+        if store.exists(&key_filename) {
+            return Ok(Some(store.read(&key_filename)?));
+        }
+    }
+    match current_cert(store, &profile)? {
+        Some(ref bundle) if !bundle.key.is_empty() => Ok(Some(bundle.key.clone())),
+        _ => Ok(None),
+    }
+}
+
+// Issues certificate of given type and installs it into store
+pub fn issue_cert(
+    store: &dyn Store,
+    ca: &CA,
+    config: &Config,
+    cert_type: &CertType,
+    csr: Option<&X509ReqRef>,
+    key: Option<&[u8]>,
+) -> Result<Box<Bundle>, Error> {
+    issue_profile(store, &ca, &profile(&config, &cert_type), csr, key)
+}
+
+// Issues certificate of profile, which can be adjusted by caller, and installs it into store
+pub fn issue_profile(
+    store: &dyn Store,
+    ca: &CA,
+    profile: &Profile,
+    csr: Option<&X509ReqRef>,
+    key: Option<&[u8]>,
+) -> Result<Box<Bundle>, Error> {
+    let config = profile.config;
+    let bundle = profile.issue(&ca, next_sn(store, &config, &profile.ca_dir())?, csr, key)?;
+
+    install_cert(store, &profile, &bundle)?;
+    if config.serial_numbers == SerialNumbers::Counter {
+        let index_filename = format!("{}/index", &profile.ca_dir());
+        write_sn(store, &index_filename, bundle.cert.serial_number().to_bn()?.as_ref())?;
+    }
+    Ok(bundle)
+}
+
+// Writes issued certificate into CA directory and creates symlinks and kubeconfigs for it
+pub fn install_cert(store: &dyn Store, profile: &Profile, bundle: &Box<Bundle>) -> Result<(), Error> {
+    let config = profile.config;
+    store_bundle(store, &bundle, &profile.ca_dir(), &profile.filename, config.overwrite)?;
+    if let Some(ref symlink_path) = profile.symlink {
+        let cert_name = format!("{}-{}", &profile.filename, bundle.cert.serial_number().to_bn()?);
+        let ca_dir = format!("../CA/{}", profile.authority.dir_name());
+        link_cert(store, &ca_dir, &cert_name, &symlink_path)?;
+    }
+    write_kubeconfig(store, &profile, &bundle)?;
+    for &(ref source, ref destination) in profile.links.iter() {
+        store.link(&source, &destination)?;
+    }
+    Ok(())
+}
+
+// Links "<dest>.crt" and "<dest>.key" to certificate and key in CA directory relative to dest
+fn link_cert(store: &dyn Store, ca_dir: &str, cert_name: &str, dest: &str) -> Result<(), Error> {
+    let types = [("key", "keys"), ("crt", "certs")];
+    for postfix in types.iter() {
+        let source_filename = format!("{}/{}/{}.{}", &ca_dir, &postfix.1, &cert_name, &postfix.0);
+        let dest_filename = format!("{}.{}", &dest, &postfix.0);
+
+        // There is no key file if certificate was issued for signing request.
+        // Drop link to previous key, it doesn't match new certificate.
+        let source_path = match Path::new(&dest_filename).parent() {
+            Some(dir) => dir.join(&source_filename),
+            None => Path::new(&source_filename).to_path_buf(),
+        };
+        if !store.exists(&source_path.to_string_lossy()) {
+            if store.is_link(&dest_filename) {
+                store.remove(&dest_filename)?;
+            }
+            continue;
+        }
+        store.link(&source_filename, &dest_filename)?;
+    }
+    Ok(())
+}
+
+// Writes kubeconfig of profile with certificates trusted for its CA
+pub fn write_kubeconfig(store: &dyn Store, profile: &Profile, bundle: &Box<Bundle>) -> Result<(), Error> {
+    let config = profile.config;
+    if let Some(ref kubeconfig) = profile.kubeconfig {
+        let trust_file = profile.authority.trust_file(store, &config.out_dir);
+        let ca_data = store.read(&format!("{}/{}", &config.out_dir, &trust_file))?;
+        // Kubeconfig can't be written without private key
+        if let Some(yaml) = profile.kubeconfig(&bundle, &ca_data)? {
+            store.write(&kubeconfig.filename, yaml.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+// Links CA certificates to every place they are expected by cluster components
+pub fn link_ca_certs(store: &dyn Store, config: &Config) -> Result<(), Error> {
+    let out_dir = &config.out_dir;
+    let mut links = vec![
+        (Authority::Root, format!("{}/master/ca.crt", out_dir)),
+        (Authority::Etcd, format!("{}/master/etcd-ca.crt", out_dir)),
+        (Authority::Etcd, format!("{}/etcd-users/etcd-ca.crt", out_dir)),
+        (Authority::FrontProxy, format!("{}/master/front-proxy-ca.crt", out_dir)),
+    ];
+    for instance in config.worker.iter() {
+        links.push((Authority::Root, format!("{}/{}/ca.crt", out_dir, instance.dirname())));
+    }
+    for instance in config.etcd_server.iter() {
+        links.push((Authority::Etcd, format!("{}/{}/etcd-ca.crt", out_dir, instance.dirname())));
+    }
+
+    for (authority, destination) in links {
+        let ca_cert_filename = format!("{}/CA/{}/certs/ca.crt", out_dir, authority.dir_name());
+        if store.exists(&ca_cert_filename) {
+            let source = format!("../{}", authority.trust_file(store, &out_dir));
+            store.link(&source, &destination)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_cert_of, store_bundle};
+    use {CertificateParameters, Database, KeyAlgorithm, MemoryStore, Store};
+
+    #[test]
+    fn cert_filenames() {
+        assert!(is_cert_of("certs/admin-1.crt", "admin"));
+        assert!(is_cert_of("certs/admin-200314872683104719.crt", "admin"));
+        assert!(is_cert_of("certs/kubelet-s1-5.crt", "kubelet-s1"));
+        assert!(!is_cert_of("certs/admin-1.key", "admin"));
+        assert!(!is_cert_of("certs/admin-.crt", "admin"));
+        assert!(!is_cert_of("certs/ca.crt", "ca"));
+        assert!(!is_cert_of("certs/ca-old.crt", "ca"));
+        // Name of other certificate can start with the same prefix
+        assert!(!is_cert_of("certs/kubelet-server-s1-6.crt", "kubelet"));
+        assert!(!is_cert_of("certs/etcd-user-calico-3.crt", "etcd-user"));
+        assert!(!is_cert_of("keys/admin-1.crt", "admin"));
+    }
+
+    #[test]
+    fn store_bundle_writes_all_or_nothing() {
+        let mut params = CertificateParameters::client("admin", 2048, 1);
+        params.key_algorithm = KeyAlgorithm::EcdsaP256;
+        let bundle = params.gen_cert().unwrap();
+        let name = format!("admin-{}", bundle.cert.serial_number().to_bn().unwrap());
+        let store = MemoryStore::new();
+
+        // Key left from earlier issuance keeps certificate from being written without database entry
+        store.write(&format!("CA/root/keys/{}.key", &name), b"old key").unwrap();
+        store_bundle(&store, &bundle, "CA/root", "admin", false).unwrap();
+        assert!(!store.exists(&format!("CA/root/certs/{}.crt", &name)));
+        assert!(Database::open(&store, "CA/root").unwrap().entries.is_empty());
+
+        store_bundle(&store, &bundle, "CA/root", "admin", true).unwrap();
+        assert!(store.exists(&format!("CA/root/certs/{}.crt", &name)));
+        assert_eq!(store.read(&format!("CA/root/keys/{}.key", &name)).unwrap(), bundle.key);
+        let database = Database::open(&store, "CA/root").unwrap();
+        assert_eq!(database.entries.len(), 1);
+        assert_eq!(database.entries[0].filename, format!("certs/{}.crt", &name));
+    }
+}
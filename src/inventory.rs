@@ -0,0 +1,223 @@
+extern crate serde_json;
+
+use cert_machine::{seconds_until, serial_hex, AuditRecord, Authority, Config, Database, Error, Status, Store};
+use openssl::nid::Nid;
+use openssl::x509::{X509NameRef, X509Ref};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+#[derive(Serialize)]
+pub struct CertInfo {
+    pub ca: String,
+    pub filename: String,
+    pub serial: String,
+    pub status: String,
+    pub common_name: Vec<String>,
+    pub organization: Vec<String>,
+    pub san: Vec<String>,
+    pub not_before: String,
+    pub not_after: String,
+    pub days_remaining: i64,
+    #[serde(skip_serializing)]
+    pub seconds_remaining: i64,
+    // Symlinks inside out_dir pointing at certificate
+    pub symlinks: Vec<String>,
+    // Who requested short-lived user certificate and for how long, from "index.txt.audit"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_seconds: Option<i64>,
+}
+
+// Loads every certificate stored in CA directories
+pub fn inventory(store: &dyn Store, config: &Config) -> Result<Vec<CertInfo>, Error> {
+    let links = find_links(store, &config.out_dir)?;
+
+    let mut infos = vec![];
+    for authority in Authority::all() {
+        let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+        let certs_dir = format!("{}/certs", &ca_dir);
+        let names: Vec<String> = store
+            .list(&certs_dir)?
+            .into_iter()
+            .filter(|name| name.ends_with(".crt") && !name.contains('/'))
+            .map(|name| name[..name.len() - ".crt".len()].to_owned())
+            .collect();
+        if names.is_empty() {
+            continue;
+        }
+        let database = Database::open(store, &ca_dir)?;
+        let audit_records = database.audit_records(store)?;
+
+        for name in names {
+            let bundle = store.read_cert(&ca_dir, &name)?;
+            let crt_filename = format!("{}/{}.crt", &certs_dir, &name);
+            let symlinks = match links.get(&store.resolve(&crt_filename)?) {
+                Some(symlinks) => symlinks.clone(),
+                None => vec![],
+            };
+            infos.push(cert_info(authority, &crt_filename, &bundle.cert, &database, &audit_records, symlinks)?);
+        }
+    }
+    Ok(infos)
+}
+
+fn cert_info(
+    authority: Authority,
+    filename: &str,
+    cert: &X509Ref,
+    database: &Database,
+    audit_records: &[AuditRecord],
+    symlinks: Vec<String>,
+) -> Result<CertInfo, Error> {
+    let serial = cert.serial_number().to_bn()?;
+    let serial_hex = serial_hex(&serial)?;
+    let audit = audit_records.iter().rev().find(|record| record.serial == serial_hex);
+    let seconds_remaining = seconds_until(cert.not_after())?;
+    let status = match database.find(&serial)? {
+        Some(entry) if entry.status == Status::Revoked => "revoked",
+        _ if seconds_remaining < 0 => "expired",
+        Some(_) => "valid",
+        None => "unknown",
+    };
+    Ok(CertInfo {
+        ca: authority.dir_name().to_owned(),
+        filename: filename.to_owned(),
+        serial: serial_hex.clone(),
+        status: status.to_owned(),
+        common_name: name_entries(cert.subject_name(), Nid::COMMONNAME)?,
+        organization: name_entries(cert.subject_name(), Nid::ORGANIZATIONNAME)?,
+        san: alt_names(&cert),
+        not_before: cert.not_before().to_string(),
+        not_after: cert.not_after().to_string(),
+        days_remaining: seconds_remaining / 86400,
+        seconds_remaining,
+        symlinks,
+        requester: audit.map(|record| record.requester.clone()),
+        ttl_seconds: audit.map(|record| record.ttl),
+    })
+}
+
+fn name_entries(name: &X509NameRef, nid: Nid) -> Result<Vec<String>, Error> {
+    let mut entries = vec![];
+    for entry in name.entries_by_nid(nid) {
+        entries.push(String::from_utf8_lossy(entry.data().as_slice()).into_owned());
+    }
+    Ok(entries)
+}
+
+// SAN entries in the form they are written in config
+fn alt_names(cert: &X509Ref) -> Vec<String> {
+    let mut san = vec![];
+    if let Some(names) = cert.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(dns) = name.dnsname() {
+                san.push(format!("dns:{}", dns));
+            } else if let Some(ip) = name.ipaddress() {
+                let ip = match ip.len() {
+                    4 => {
+                        let mut octets = [0u8; 4];
+                        octets.copy_from_slice(&ip);
+                        IpAddr::from(octets).to_string()
+                    }
+                    16 => {
+                        let mut octets = [0u8; 16];
+                        octets.copy_from_slice(&ip);
+                        IpAddr::from(octets).to_string()
+                    }
+                    _ => continue,
+                };
+                san.push(format!("ip:{}", ip));
+            } else if let Some(uri) = name.uri() {
+                san.push(format!("uri:{}", uri));
+            } else if let Some(email) = name.email() {
+                san.push(format!("email:{}", email));
+            }
+        }
+    }
+    san
+}
+
+// Collects links under out_dir by name of file they resolve to, CA directory is skipped.
+// Links are given relative to out_dir.
+fn find_links(store: &dyn Store, out_dir: &str) -> Result<HashMap<String, Vec<String>>, Error> {
+    let mut links = HashMap::new();
+    for name in store.list(&out_dir)? {
+        let link = format!("{}/{}", &out_dir, &name);
+        // Dangling links can't point at any certificate
+        if name.starts_with("CA/") || !store.is_link(&link) || !store.exists(&link) {
+            continue;
+        }
+        links.entry(store.resolve(&link)?).or_insert_with(Vec::new).push(name);
+    }
+    Ok(links)
+}
+
+pub fn list(store: &dyn Store, config: &Config, json: bool) -> Result<(), Error> {
+    let infos = inventory(store, &config)?;
+    if json {
+        match serde_json::to_string_pretty(&infos) {
+            Ok(output) => println!("{}", output),
+            Err(err) => return Err(Error::Config(format!("Unable to serialize certificate list: {}", err))),
+        }
+        return Ok(());
+    }
+    for info in infos.iter() {
+        println!("{} {} ({})", &info.filename, &info.serial, &info.status);
+        println!("    CA: {}", &info.ca);
+        println!("    CN: {}", info.common_name.join(", "));
+        if !info.organization.is_empty() {
+            println!("    O: {}", info.organization.join(", "));
+        }
+        if !info.san.is_empty() {
+            println!("    SAN: {}", info.san.join(", "));
+        }
+        println!("    Not before: {}", &info.not_before);
+        println!("    Not after: {} ({} days remaining)", &info.not_after, info.days_remaining);
+        if !info.symlinks.is_empty() {
+            println!("    Symlinks: {}", info.symlinks.join(", "));
+        }
+        if let (&Some(ref requester), Some(ttl)) = (&info.requester, info.ttl_seconds) {
+            println!("    Requested by: {} (TTL {}s)", &requester, ttl);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::{CertType, DryRunStore, MemoryStore};
+    use create_ca;
+    use kubernetes_certs::gen_cert;
+    use testing;
+
+    #[test]
+    fn memory_store_inventory() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let store = MemoryStore::new();
+        let ca = create_ca(&store, &config).unwrap();
+        let apiserver = gen_cert(&store, &ca, &config, &CertType::ApiServer).unwrap();
+
+        let infos = inventory(&store, &config).unwrap();
+        assert_eq!(infos.len(), 4);
+        let root_ca = infos.iter().find(|info| info.filename == "out/CA/root/certs/ca.crt").unwrap();
+        assert_eq!(root_ca.symlinks, vec!["master/ca.crt"]);
+        let serial = serial_hex(&apiserver.cert.serial_number().to_bn().unwrap()).unwrap();
+        let info = infos.iter().find(|info| info.serial == serial).unwrap();
+        assert_eq!(info.ca, "root");
+        assert_eq!(info.status, "valid");
+        assert_eq!(info.common_name, vec!["kubernetes"]);
+        assert_eq!(info.symlinks, vec!["master/apiserver.crt"]);
+        assert!(info.san.contains(&"ip:10.0.0.1".to_owned()));
+        assert!(info.days_remaining >= 29);
+
+        // Certificates planned by dry run are listed, underlying store is untouched
+        let dry_run = DryRunStore::new(&store);
+        gen_cert(&dry_run, &ca, &config, &CertType::Admin).unwrap();
+        let planned = inventory(&dry_run, &config).unwrap();
+        assert_eq!(planned.len(), 5);
+        assert!(planned.iter().any(|info| info.common_name == vec!["admin"]));
+        assert_eq!(inventory(&store, &config).unwrap().len(), 4);
+    }
+}
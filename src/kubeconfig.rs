@@ -1,11 +1,9 @@
 extern crate serde_yaml;
 extern crate base64;
 
-use std::path::Path;
-use std::io;
-use std::fs;
 use self::base64::encode;
 use Bundle;
+use Error;
 
 #[derive(Debug, Serialize)]
 struct Kubeconfig<'a> {
@@ -63,30 +61,31 @@ pub struct KubeconfigParameters<'a> {
     pub cluster_name: &'a str,
     pub username: &'a str,
     pub cert: &'a Box<Bundle>,
-    pub ca_cert: &'a Box<Bundle>,
-    pub kubeconfig_filename: &'a str,
+    // PEM encoded CA certificates trusted by client
+    pub ca_data: &'a [u8],
 }
 
-pub fn create_kubeconfig(config: &KubeconfigParameters) -> Result<String, io::Error> {
+// Renders kubeconfig YAML, writing it is left to caller
+pub fn create_kubeconfig(config: &KubeconfigParameters) -> Result<String, Error> {
     let apiserver_address = format!("https://{}", &config.apiserver_address);
-    let client_certificate_data = encode(&config.cert.cert.to_pem().unwrap());
+    let client_certificate_data = encode(&config.cert.to_pem()?);
     let client_key_data = encode(&config.cert.key);
     let kubeconfig = Kubeconfig {
 		api_vesrsion: "v1",
 		clusters: vec![
 			Cluster {
 				cluster: ClusterParameters {
-                    certificate_authority_data: encode(&config.ca_cert.cert.to_pem().unwrap()),
+                    certificate_authority_data: encode(&config.ca_data),
                     server: &apiserver_address,
                 },
-				name: config.cluster_name.clone(),
+				name: config.cluster_name,
 			}
 		],
 		contexts: vec![
 			Context {
 				context: ContextParameters {
-                    cluster: config.cluster_name.clone(),
-                    user: config.username.clone(),
+                    cluster: config.cluster_name,
+                    user: config.username,
                 },
 				name: "default",
 			}
@@ -95,7 +94,7 @@ pub fn create_kubeconfig(config: &KubeconfigParameters) -> Result<String, io::Er
 		kind: "Config",
 		users: vec![
 			User {
-				name: config.username.clone(),
+				name: config.username,
                 user: UserParameters {
                     client_certificate_data: &client_certificate_data,
                     client_key_data: &client_key_data,
@@ -104,8 +103,8 @@ pub fn create_kubeconfig(config: &KubeconfigParameters) -> Result<String, io::Er
 		],
     };
 
-    let kubeconfig_yaml = serde_yaml::to_string(&kubeconfig).unwrap();
-    let path = Path::new(&config.kubeconfig_filename);
-    fs::write(&path, &kubeconfig_yaml).unwrap();
-    Ok(kubeconfig_yaml)
+    match serde_yaml::to_string(&kubeconfig) {
+        Ok(yaml) => Ok(yaml),
+        Err(err) => Err(Error::Config(format!("Unable to serialize kubeconfig: {}", err))),
+    }
 }
@@ -0,0 +1,548 @@
+use config::{Config, Instance, Overrides, User};
+use kubeconfig::{create_kubeconfig, KubeconfigParameters};
+use openssl::bn::BigNum;
+use pkcs11;
+use drift::Field;
+use openssl::x509::{X509ReqRef, X509Ref};
+use {AltName, Bundle, CertificateParameters, Error, KeyAlgorithm, Passphrase, Store, Subject};
+
+pub enum CertType<'a> {
+    Admin,
+    ApiServer,
+    ApiServerClient,
+    ApiServerEtcdClient,
+    ControllerManager,
+    FrontProxy,
+    Scheduler,
+    Proxy,
+    EtcdServer(&'a Instance),
+    EtcdUser(&'a str),
+    Kubelet(&'a Instance),
+    KubeletServer(&'a Instance),
+    User(&'a User),
+}
+
+fn opt_str(opt_string: &Option<String>) -> Option<&str> {
+    match opt_string {
+        Some(s) => Some(s.as_ref()),
+        None => None,
+    }
+}
+
+pub fn gen_main_ca_cert(config: &Config) -> Result<Box<Bundle>, Error> {
+    let key = token_key(&config, Authority::Root)?;
+    let ca_cert = CertificateParameters {
+        key_algorithm: config.ca.key_algorithm,
+        key_length: config.ca.key_size,
+        // CA certificates always get random serial number, counter of root CA doesn't exist yet
+        // and its successor after rotation must not repeat serial number of the same issuer
+        serial_number: None,
+        validity_days: config.ca.validity_days,
+        validity_seconds: None,
+        not_before_skew: config.not_before_skew_seconds,
+        not_before: None,
+        not_after: None,
+        cap_validity_at_ca: true,
+        subject: Subject {
+            common_name: &config.cluster_name,
+            country: opt_str(&config.ca.country),
+            organization: config.ca.organization.iter().map(|value| value.as_str()).collect(),
+            organization_unit: config.ca.organization_unit.iter().map(|value| value.as_str()).collect(),
+            state_or_province_name: opt_str(&config.ca.state_or_province_name),
+            locality: opt_str(&config.ca.locality),
+        },
+        key_usage: vec![
+            "digital_signature",
+            "key_encipherment",
+            "key_cert_sign",
+            "crl_sign",
+            "critical",
+        ],
+        extended_key_usage: None,
+        basic_constraints: Some(vec!["ca"]),
+        san: None,
+        ca: None,
+        csr: None,
+        key: key.as_ref().map(|key| key.as_slice()),
+    };
+
+    ca_cert.gen_cert()
+}
+
+// Intermediate CA signed by main one
+pub fn gen_ca_cert(authority: Authority, main_ca: Option<&Box<Bundle>>, config: &Config) -> Result<Box<Bundle>, Error> {
+    let cn = match authority {
+        Authority::Root => &config.cluster_name,
+        Authority::Etcd => "etcd",
+        Authority::FrontProxy => "front-proxy-ca",
+    };
+    let key = token_key(&config, authority)?;
+    let mut ca_cert = CertificateParameters::ca(&cn, config.ca.key_size, config.ca.validity_days);
+    ca_cert.key_algorithm = config.ca.key_algorithm;
+    ca_cert.ca = main_ca;
+    ca_cert.key = key.as_ref().map(|key| key.as_slice());
+    ca_cert.not_before_skew = config.not_before_skew_seconds;
+    ca_cert.gen_cert()
+}
+
+// Reference to key in PKCS#11 token if CA is kept there
+fn token_key(config: &Config, authority: Authority) -> Result<Option<Vec<u8>>, Error> {
+    match config.ca.pkcs11 {
+        Some(ref pkcs11) if pkcs11.cas.iter().any(|ca| ca == authority.dir_name()) => Ok(Some(pkcs11::token_key(
+            &pkcs11,
+            authority.dir_name(),
+            config.ca.key_algorithm,
+            config.ca.key_size,
+        )?)),
+        _ => Ok(None),
+    }
+}
+
+// Certificate authorities of the cluster
+#[derive(Clone, Copy, PartialEq)]
+pub enum Authority {
+    Root,
+    Etcd,
+    FrontProxy,
+}
+
+impl Authority {
+    pub fn all() -> Vec<Authority> {
+        vec![Authority::Root, Authority::Etcd, Authority::FrontProxy]
+    }
+
+    // Directory name of CA inside "<out_dir>/CA"
+    pub fn dir_name(&self) -> &'static str {
+        match *self {
+            Authority::Root => "root",
+            Authority::Etcd => "etcd",
+            Authority::FrontProxy => "front-proxy",
+        }
+    }
+
+    // Certificates which clients should trust for this CA, relative to out_dir.
+    // While CA is rotated it is bundle of old and new CA certificates.
+    pub fn trust_file(&self, store: &dyn Store, out_dir: &str) -> String {
+        let bundle = format!("CA/{}/ca-bundle.crt", self.dir_name());
+        match store.exists(&format!("{}/{}", &out_dir, &bundle)) {
+            true => bundle,
+            false => format!("CA/{}/certs/ca.crt", self.dir_name()),
+        }
+    }
+}
+
+pub enum Usage {
+    Client,
+    Server,
+    ClientAndServer,
+}
+
+pub struct KubeconfigProfile<'a> {
+    pub filename: String,
+    pub apiserver_address: &'a str,
+    pub username: String,
+}
+
+// Describes what certificate of given type contains and where it is placed
+pub struct Profile<'a> {
+    pub description: String,
+    pub common_name: String,
+    // Kubernetes groups
+    pub organization: Vec<String>,
+    pub san: Option<Vec<String>>,
+    pub usage: Usage,
+    pub authority: Authority,
+    pub validity_days: u32,
+    // Validity in seconds for short-lived certificates, replaces validity_days if set
+    pub validity_seconds: Option<u32>,
+    pub key_size: u32,
+    pub key_algorithm: KeyAlgorithm,
+    // Certificate and key are stored as "<filename>-<sn>" in CA directory
+    pub filename: String,
+    // Path (without extension) of symlinks to current cert and key
+    pub symlink: Option<String>,
+    pub kubeconfig: Option<KubeconfigProfile<'a>>,
+    // Additional symlinks as (source, destination)
+    pub links: Vec<(String, String)>,
+    pub config: &'a Config,
+}
+
+impl<'a> Profile<'a> {
+    pub fn params(&self) -> CertificateParameters {
+        let mut params = match self.usage {
+            Usage::Client => CertificateParameters::client(&self.common_name, self.key_size, self.validity_days),
+            Usage::Server => CertificateParameters::server(&self.common_name, self.key_size, self.validity_days),
+            Usage::ClientAndServer => {
+                CertificateParameters::client_and_server(&self.common_name, self.key_size, self.validity_days)
+            }
+        };
+        params.key_algorithm = self.key_algorithm;
+        params.validity_seconds = self.validity_seconds;
+        params.not_before_skew = self.config.not_before_skew_seconds;
+        params.not_before = opt_str(&self.config.not_before);
+        params.not_after = opt_str(&self.config.not_after);
+        params.cap_validity_at_ca = self.config.cap_validity_at_ca;
+        params.subject.organization = self.organization.iter().map(|value| value.as_str()).collect();
+        if let Some(ref san) = self.san {
+            params.san = Some(san.iter().map(|s| s as &str).collect());
+        }
+        params
+    }
+
+    // Whether certificate has subject and SAN which profile gives now, e.g. config wasn't changed since it was issued
+    pub fn matches(&self, cert: &X509Ref) -> Result<bool, Error> {
+        let mismatches = self.mismatches(&cert, None)?;
+        Ok(!mismatches.iter().any(|mismatch| mismatch.field == Field::Subject || mismatch.field == Field::San))
+    }
+
+    pub fn ca_dir(&self) -> String {
+        format!("{}/CA/{}", &self.config.out_dir, self.authority.dir_name())
+    }
+
+    // Issues certificate of profile in memory. Random serial number is used if none given,
+    // existing PEM encoded key or signing request is used instead of new key if present.
+    pub fn issue(
+        &self,
+        ca: &CA,
+        serial_number: Option<BigNum>,
+        csr: Option<&X509ReqRef>,
+        key: Option<&[u8]>,
+    ) -> Result<Box<Bundle>, Error> {
+        let mut params = self.params();
+        params.serial_number = serial_number;
+        params.ca = Some(ca.bundle(self.authority));
+        params.csr = csr;
+        params.key = key;
+        params.gen_cert()
+    }
+
+    // Kubeconfig YAML of profile for given certificate and PEM encoded trusted CA certificates.
+    // None if profile has no kubeconfig or certificate has no private key.
+    pub fn kubeconfig(&self, bundle: &Box<Bundle>, ca_data: &[u8]) -> Result<Option<String>, Error> {
+        let kubeconfig = match self.kubeconfig {
+            Some(ref kubeconfig) if !bundle.key.is_empty() => kubeconfig,
+            _ => return Ok(None),
+        };
+        let kubeconfig_parameters = KubeconfigParameters {
+            apiserver_address: kubeconfig.apiserver_address,
+            cluster_name: &self.config.cluster_name,
+            username: &kubeconfig.username,
+            cert: &bundle,
+            ca_data: &ca_data,
+        };
+        Ok(Some(create_kubeconfig(&kubeconfig_parameters)?))
+    }
+}
+
+pub struct CA {
+    pub main_ca: Box<Bundle>,
+    pub etcd_ca: Box<Bundle>,
+    pub front_ca: Box<Bundle>,
+}
+
+impl CA {
+    // Generates root CA and etcd and front proxy CAs signed by it, nothing is written to filesystem
+    pub fn generate(config: &Config) -> Result<CA, Error> {
+        let main_ca = gen_main_ca_cert(&config)?;
+        let etcd_ca = gen_ca_cert(Authority::Etcd, Some(&main_ca), &config)?;
+        let front_ca = gen_ca_cert(Authority::FrontProxy, Some(&main_ca), &config)?;
+        Ok(CA {
+            main_ca,
+            etcd_ca,
+            front_ca,
+        })
+    }
+
+    // Reads CAs from "<dir>/CA/<ca>"
+    pub fn read(store: &dyn Store, dir: &str) -> Result<CA, Error> {
+        let main_ca_dir = format!("{}/CA/root", &dir);
+        let etcd_ca_dir = format!("{}/CA/etcd", &dir);
+        let front_ca_dir = format!("{}/CA/front-proxy", &dir);
+        Ok(CA {
+            main_ca: store.read_bundle(&main_ca_dir, "ca")?,
+            etcd_ca: store.read_bundle(&etcd_ca_dir, "ca")?,
+            front_ca: store.read_bundle(&front_ca_dir, "ca")?,
+        })
+    }
+
+    // Reads CAs from "<dir>/CA/<ca>" in local filesystem, encrypted keys are decrypted with given passphrase
+    pub fn read_from_fs(dir: &str, passphrase: &Passphrase) -> Result<CA, Error> {
+        Ok(CA {
+            main_ca: Bundle::read_from_fs(&format!("{}/CA/root", &dir), "ca", &passphrase)?,
+            etcd_ca: Bundle::read_from_fs(&format!("{}/CA/etcd", &dir), "ca", &passphrase)?,
+            front_ca: Bundle::read_from_fs(&format!("{}/CA/front-proxy", &dir), "ca", &passphrase)?,
+        })
+    }
+
+    pub fn bundle(&self, authority: Authority) -> &Box<Bundle> {
+        match authority {
+            Authority::Root => &self.main_ca,
+            Authority::Etcd => &self.etcd_ca,
+            Authority::FrontProxy => &self.front_ca,
+        }
+    }
+
+    // Issues certificate of given type with random serial number, nothing is written to filesystem
+    pub fn issue(&self, config: &Config, cert_type: &CertType) -> Result<Box<Bundle>, Error> {
+        profile(&config, &cert_type).issue(&self, None, None, None)
+    }
+
+    // Issues certificate of given type for public key of signing request
+    pub fn sign_csr(&self, config: &Config, cert_type: &CertType, csr: &X509ReqRef) -> Result<Box<Bundle>, Error> {
+        profile(&config, &cert_type).issue(&self, None, Some(csr), None)
+    }
+}
+
+impl<'a> CertType<'a> {
+    // Unique name of certificate within the cluster
+    pub fn name(&self) -> String {
+        match *self {
+            CertType::Admin => "admin".to_owned(),
+            CertType::ApiServer => "apiserver".to_owned(),
+            CertType::ApiServerClient => "apiserver-kubelet-client".to_owned(),
+            CertType::ApiServerEtcdClient => "apiserver-etcd-client".to_owned(),
+            CertType::ControllerManager => "kube-controller-manager".to_owned(),
+            CertType::FrontProxy => "front-proxy-client".to_owned(),
+            CertType::Scheduler => "kube-scheduler".to_owned(),
+            CertType::Proxy => "kube-proxy".to_owned(),
+            CertType::EtcdServer(instance) => format!("etcd-{}", instance.dirname()),
+            CertType::EtcdUser(username) => format!("etcd-user-{}", username),
+            CertType::Kubelet(instance) => format!("kubelet-{}", instance.dirname()),
+            CertType::KubeletServer(instance) => format!("kubelet-server-{}", instance.dirname()),
+            CertType::User(user) => format!("user-{}", &user.username),
+        }
+    }
+
+    // Kind of certificate as given to gen-cert, also name of its [components.<kind>] table
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            CertType::Admin => "admin",
+            CertType::ApiServer => "apiserver",
+            CertType::ApiServerClient => "apiserver-client",
+            CertType::ApiServerEtcdClient => "apiserver-etcd-client",
+            CertType::ControllerManager => "controller-manager",
+            CertType::Scheduler => "scheduler",
+            CertType::FrontProxy => "front-proxy-client",
+            CertType::Proxy => "proxy",
+            CertType::Kubelet(_) | CertType::KubeletServer(_) => "kubelet",
+            CertType::EtcdServer(_) => "etcd",
+            CertType::EtcdUser(_) => "etcd-user",
+            CertType::User(_) => "user",
+        }
+    }
+}
+
+// Returns every certificate defined by config
+pub fn all_cert_types(config: &Config) -> Vec<CertType> {
+    let mut cert_types = vec![
+        CertType::Admin,
+        CertType::ApiServer,
+        CertType::ApiServerClient,
+        CertType::ApiServerEtcdClient,
+        CertType::ControllerManager,
+        CertType::Scheduler,
+        CertType::FrontProxy,
+        CertType::Proxy,
+    ];
+    for instance in config.worker.iter() {
+        cert_types.push(CertType::Kubelet(&instance));
+        cert_types.push(CertType::KubeletServer(&instance));
+    }
+    for instance in config.etcd_server.iter() {
+        cert_types.push(CertType::EtcdServer(&instance));
+    }
+    if let Some(ref users) = config.user {
+        for user in users {
+            cert_types.push(CertType::User(&user));
+        }
+    }
+    if let Some(ref users) = config.etcd_users {
+        for user in users {
+            cert_types.push(CertType::EtcdUser(&user));
+        }
+    }
+    cert_types
+}
+
+pub fn profile<'a>(config: &'a Config, cert_type: &CertType) -> Profile<'a> {
+    let out_dir = &config.out_dir;
+    let mut profile = Profile {
+        description: String::new(),
+        common_name: String::new(),
+        organization: vec![],
+        san: None,
+        usage: Usage::Client,
+        authority: Authority::Root,
+        validity_days: config.validity_days,
+        validity_seconds: None,
+        key_size: config.key_size,
+        key_algorithm: config.key_algorithm,
+        filename: String::new(),
+        symlink: None,
+        kubeconfig: None,
+        links: vec![],
+        config,
+    };
+    match *cert_type {
+        CertType::Admin => {
+            profile.description = "Kubernetes admin".to_owned();
+            profile.common_name = "admin".to_owned();
+            profile.organization = vec!["system:masters".to_owned()];
+            profile.filename = "admin".to_owned();
+            profile.kubeconfig = Some(KubeconfigProfile {
+                filename: format!("{}/users/admin.kubeconfig", out_dir),
+                apiserver_address: &config.apiserver_external_address,
+                username: "admin".to_owned(),
+            });
+        },
+        CertType::ApiServer => {
+            let mut san: Vec<String> = vec![
+                "kubernetes".to_owned(),
+                "kubernetes.default".to_owned(),
+                "kubernetes.default.svc".to_owned(),
+                format!("kubernetes.default.svc.{}", &config.cluster_domain),
+            ];
+            // Service CIDRs are checked when config is read
+            for ip in config.service_ips().unwrap_or_default() {
+                san.push(ip.to_string());
+            }
+            // Service IP could be listed in master_san by hand before it was computed
+            for entry in config.master_san.iter() {
+                let alt_name = AltName::parse(&entry).ok();
+                if !san.iter().any(|name| AltName::parse(&name).ok() == alt_name) {
+                    san.push(entry.clone());
+                }
+            }
+            profile.description = "Kubernetes API server".to_owned();
+            profile.common_name = "kubernetes".to_owned();
+            profile.san = Some(san);
+            profile.usage = Usage::Server;
+            profile.filename = "apiserver".to_owned();
+            profile.symlink = Some(format!("{}/master/apiserver", out_dir));
+        },
+        CertType::ApiServerClient => {
+            profile.description = "Kubernetes API server kubelet client".to_owned();
+            profile.common_name = "kube-apiserver-kubelet-client".to_owned();
+            profile.organization = vec!["system:masters".to_owned()];
+            profile.filename = "apiserver-kubelet-client".to_owned();
+            profile.symlink = Some(format!("{}/master/apiserver-kubelet-client", out_dir));
+        },
+        CertType::ApiServerEtcdClient => {
+            profile.description = "Kubernetes ETCD client".to_owned();
+            // If etcd auth enable and apiserver etcd username is not root
+            // apiserver can't compact etcd storage
+            profile.common_name = "root".to_owned();
+            profile.organization = vec!["system:masters".to_owned()];
+            profile.authority = Authority::Etcd;
+            profile.filename = "apiserver-etcd-client".to_owned();
+            profile.symlink = Some(format!("{}/master/apiserver-etcd-client", out_dir));
+        },
+        CertType::ControllerManager => {
+            profile.description = "Kubernetes controller-manager".to_owned();
+            profile.common_name = "system:kube-controller-manager".to_owned();
+            profile.organization = vec!["system:masters".to_owned()];
+            profile.filename = "kube-controller-manager".to_owned();
+            profile.kubeconfig = Some(KubeconfigProfile {
+                filename: format!("{}/master/kube-controller-manager.kubeconfig", out_dir),
+                apiserver_address: &config.apiserver_internal_address,
+                username: "system:kube-controller-manager".to_owned(),
+            });
+        },
+        CertType::Scheduler => {
+            profile.description = "Kubernetes scheduler".to_owned();
+            profile.common_name = "system:kube-scheduler".to_owned();
+            profile.organization = vec!["system:masters".to_owned()];
+            profile.filename = "kube-scheduler".to_owned();
+            profile.kubeconfig = Some(KubeconfigProfile {
+                filename: format!("{}/master/kube-scheduler.kubeconfig", out_dir),
+                apiserver_address: &config.apiserver_internal_address,
+                username: "system:kube-scheduler".to_owned(),
+            });
+        },
+        CertType::Proxy => {
+            profile.description = "Kubernetes proxy".to_owned();
+            profile.common_name = "system:kube-proxy".to_owned();
+            profile.organization = vec!["system:node-proxier".to_owned()];
+            profile.filename = "kube-proxy".to_owned();
+            profile.kubeconfig = Some(KubeconfigProfile {
+                filename: format!("{}/kube-proxy.kubeconfig", out_dir),
+                apiserver_address: &config.apiserver_internal_address,
+                username: "system:kube-proxy".to_owned(),
+            });
+        },
+        CertType::FrontProxy => {
+            profile.description = "front-proxy-client".to_owned();
+            profile.common_name = "front-proxy-client".to_owned();
+            profile.authority = Authority::FrontProxy;
+            profile.filename = "front-proxy-client".to_owned();
+            profile.symlink = Some(format!("{}/master/front-proxy-client", out_dir));
+        },
+        CertType::EtcdServer(instance) => {
+            profile.description = format!("etcd node: {}", &instance.hostname);
+            profile.common_name = instance.hostname.clone();
+            profile.san = Some(instance.san.clone());
+            profile.usage = Usage::ClientAndServer;
+            profile.authority = Authority::Etcd;
+            profile.filename = instance.dirname().to_owned();
+            profile.symlink = Some(format!("{}/{}/etcd", out_dir, instance.dirname()));
+        },
+        CertType::EtcdUser(username) => {
+            profile.description = format!("etcd user: {}", &username);
+            profile.common_name = username.to_owned();
+            profile.authority = Authority::Etcd;
+            profile.filename = username.to_owned();
+            profile.symlink = Some(format!("{}/etcd-users/{}", out_dir, &username));
+        },
+        CertType::Kubelet(instance) => {
+            let cn = format!("system:node:{}", &instance.hostname);
+            profile.description = format!("node: {}", &instance.hostname);
+            profile.common_name = cn.clone();
+            profile.organization = vec!["system:nodes".to_owned()];
+            profile.filename = instance.dirname().to_owned();
+            profile.kubeconfig = Some(KubeconfigProfile {
+                filename: format!("{}/{}/node.kubeconfig", out_dir, instance.dirname()),
+                apiserver_address: &config.apiserver_internal_address,
+                username: cn,
+            });
+            profile.links.push((
+                "../kube-proxy.kubeconfig".to_owned(),
+                format!("{}/{}/kube-proxy.kubeconfig", out_dir, instance.dirname()),
+            ));
+        },
+        CertType::KubeletServer(instance) => {
+            profile.description = format!("node server: {}", &instance.hostname);
+            profile.common_name = instance.hostname.clone();
+            profile.san = Some(instance.san.clone());
+            profile.usage = Usage::Server;
+            profile.filename = instance.dirname().to_owned();
+            profile.symlink = Some(format!("{}/{}/node", out_dir, instance.dirname()));
+        },
+        CertType::User(user) => {
+            profile.description = format!("kubernetes user: {}", &user.username);
+            profile.common_name = user.username.clone();
+            profile.organization = user.groups.clone();
+            profile.filename = user.username.clone();
+            profile.kubeconfig = Some(KubeconfigProfile {
+                filename: format!("{}/users/{}.kubeconfig", out_dir, &user.username),
+                apiserver_address: &config.apiserver_external_address,
+                username: user.username.clone(),
+            });
+        },
+    }
+
+    // Settings of instance or user entry take precedence over ones of component
+    let entry = match *cert_type {
+        CertType::Kubelet(instance) | CertType::KubeletServer(instance) | CertType::EtcdServer(instance) => {
+            instance.overrides()
+        }
+        CertType::User(user) => user.overrides(),
+        _ => Overrides::default(),
+    };
+    let component = config.components.get(cert_type.kind()).cloned().unwrap_or_default();
+    let overrides = entry.or(component);
+    profile.validity_days = overrides.validity_days.unwrap_or(config.validity_days);
+    profile.key_size = overrides.key_size.unwrap_or(config.key_size);
+    profile.key_algorithm = overrides.key_algorithm.unwrap_or(config.key_algorithm);
+    profile
+}
+
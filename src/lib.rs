@@ -1,72 +1,268 @@
 extern crate openssl;
+extern crate openssl_sys as ffi;
+extern crate foreign_types;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
+mod config;
+mod crl;
+mod database;
+mod drift;
+mod encryption;
+mod error;
+mod install;
+mod kubeconfig;
+mod kubernetes;
+mod pkcs11;
+mod store;
+#[cfg(test)]
+mod testing;
+
+pub use config::{Ca, Config, Instance, KeyEncryption, Overrides, Pkcs11, SerialNumbers, User};
+pub use crl::gen_crl;
+pub use database::{serial_hex, AuditRecord, Database, Entry, RevocationReason, Status};
+pub use drift::{Field, Mismatch};
+pub use encryption::{decrypt_key, encrypt_key, is_encrypted, EncryptedStore, Passphrase, PASSPHRASE_ENV};
+pub use error::Error;
+pub use install::{
+    current_cert, current_key, install_cert, is_cert_of, issue_cert, issue_profile, link_ca_certs, store_bundle,
+    write_kubeconfig,
+};
+pub use kubeconfig::{create_kubeconfig, KubeconfigParameters};
+pub use kubernetes::{
+    all_cert_types, gen_ca_cert, gen_main_ca_cert, profile, Authority, CertType, KubeconfigProfile, Profile, Usage, CA,
+};
+pub use pkcs11::{init_pkcs11, is_key_reference, load_private_key, PIN_ENV};
+pub use store::{DryRunStore, FileStore, MemoryStore, Store};
+
+use std::fmt;
 use std::fs;
-use std::io;
-use openssl::asn1::Asn1Time;
+use std::net::IpAddr;
+use std::os::raw::{c_int, c_long};
+use std::ptr;
+use foreign_types::{ForeignType, ForeignTypeRef};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
 use openssl::conf::Conf;
 use openssl::conf::ConfMethod;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
 use openssl::hash::MessageDigest;
 use openssl::nid::Nid;
-use openssl::pkey::PKey;
+use openssl::pkey::{HasPrivate, Id, PKey, PKeyRef};
 use openssl::pkey::Private;
 use openssl::rsa::Rsa;
-use openssl::bn::BigNum;
+use openssl::bn::{BigNum, MsbOption};
 use openssl::x509::extension::ExtendedKeyUsage;
 use openssl::x509::extension::KeyUsage;
 use openssl::x509::extension::SubjectAlternativeName;
 use openssl::x509::extension::SubjectKeyIdentifier;
 use openssl::x509::extension::AuthorityKeyIdentifier;
 use openssl::x509::extension::BasicConstraints;
+use openssl::stack::Stack;
 use openssl::x509::X509Extension;
-use openssl::x509::{X509Name, X509};
+use openssl::x509::X509v3Context;
+use openssl::x509::{X509Name, X509, X509Ref, X509Req, X509ReqRef};
 
 pub struct Bundle {
     pub cert: X509,
+    // Empty if certificate was issued for external certificate signing request
     pub key: Vec<u8>,
 }
 
 impl Bundle {
-    pub fn private_key(&self) -> PKey<Private> {
-        PKey::private_key_from_pem(&self.key).expect("Error parsing private key!")
+    // Key of CA kept in PKCS#11 token is loaded through pkcs11 engine
+    pub fn private_key(&self) -> Result<PKey<Private>, Error> {
+        load_private_key(&self.key)
     }
 
-    pub fn to_pem(&self) -> Vec<u8> {
-        self.cert.to_pem().expect("Unable to convert cert to X509!")
+    pub fn to_pem(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.cert.to_pem()?)
     }
 
-    pub fn read_from_fs(dir: &str, filename: &str) -> Result<Box<Bundle>, io::Error> {
+    // Encrypted key is decrypted with given passphrase
+    pub fn read_from_fs(dir: &str, filename: &str, passphrase: &Passphrase) -> Result<Box<Bundle>, Error> {
         let key_filename = format!("{}/keys/{}.key", &dir, &filename);
         let crt_filename = format!("{}/certs/{}.crt", &dir, &filename);
 
-        let key_file = fs::read(&key_filename)?;
+        let mut key_file = fs::read(&key_filename)?;
         let cert_file = fs::read(&crt_filename)?;
+        if is_encrypted(&key_file) {
+            key_file = decrypt_key(&key_file, &passphrase.get(false)?)?;
+        }
 
-        // let ca_key = Rsa::private_key_from_pem(&key_file).expect("Unable to parse ca.key");
-        let ca_cert = X509::from_pem(&cert_file).expect("Unable to parse ca cert.");
+        let ca_cert = X509::from_pem(&cert_file)?;
         Ok(Box::new(Bundle {
             cert: ca_cert,
             key: key_file,
         }))
     }
+
+    // Reads only certificate, key of returned bundle is empty
+    pub fn read_cert_from_fs(dir: &str, filename: &str) -> Result<Box<Bundle>, Error> {
+        let crt_filename = format!("{}/certs/{}.crt", &dir, &filename);
+        let cert_file = fs::read(&crt_filename)?;
+        Ok(Box::new(Bundle {
+            cert: X509::from_pem(&cert_file)?,
+            key: Vec::new(),
+        }))
+    }
+}
+
+// Not exported by openssl-sys yet
+const NID_ED25519: i32 = 1087;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum KeyAlgorithm {
+    #[serde(rename = "rsa")]
+    Rsa,
+    #[serde(rename = "ecdsa-p256")]
+    EcdsaP256,
+    #[serde(rename = "ecdsa-p384")]
+    EcdsaP384,
+    #[serde(rename = "ed25519")]
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    // Generates new private key. Returns key and its PEM representation
+    fn generate(&self, key_length: u32) -> Result<(PKey<Private>, Vec<u8>), ErrorStack> {
+        match *self {
+            KeyAlgorithm::Rsa => {
+                let rsa = Rsa::generate(key_length)?;
+                let key = rsa.private_key_to_pem()?;
+                Ok((PKey::from_rsa(rsa)?, key))
+            },
+            KeyAlgorithm::EcdsaP256 | KeyAlgorithm::EcdsaP384 => {
+                let curve = match *self {
+                    KeyAlgorithm::EcdsaP256 => Nid::X9_62_PRIME256V1,
+                    _ => Nid::SECP384R1,
+                };
+                let group = EcGroup::from_curve_name(curve)?;
+                let ec_key = EcKey::generate(&group)?;
+                let key = ec_key.private_key_to_pem()?;
+                Ok((PKey::from_ec_key(ec_key)?, key))
+            },
+            KeyAlgorithm::Ed25519 => {
+                let pkey = generate_ed25519()?;
+                let key = pkey.private_key_to_pem_pkcs8()?;
+                Ok((pkey, key))
+            },
+        }
+    }
+}
+
+// rust-openssl has no safe wrapper for Ed25519 key generation
+fn generate_ed25519() -> Result<PKey<Private>, ErrorStack> {
+    unsafe {
+        let ctx = ffi::EVP_PKEY_CTX_new_id(NID_ED25519, ptr::null_mut());
+        if ctx.is_null() {
+            return Err(ErrorStack::get());
+        }
+        let mut key = ptr::null_mut();
+        let generated = ffi::EVP_PKEY_keygen_init(ctx) > 0 && ffi::EVP_PKEY_keygen(ctx, &mut key) > 0;
+        ffi::EVP_PKEY_CTX_free(ctx);
+        if !generated {
+            return Err(ErrorStack::get());
+        }
+        Ok(PKey::from_ptr(key))
+    }
+}
+
+// Returns digest which should be used for signing with given key.
+// Ed25519 signs message itself, so digest must be empty.
+fn signing_digest<T: HasPrivate>(key: &PKeyRef<T>) -> MessageDigest {
+    match key.id() {
+        Id::EC if key.bits() > 256 => MessageDigest::sha384(),
+        id if id == Id::from_raw(NID_ED25519) => unsafe { MessageDigest::from_ptr(ptr::null()) },
+        _ => MessageDigest::sha256(),
+    }
+}
+
+extern "C" {
+    // Not exposed by openssl-sys
+    fn ASN1_TIME_diff(
+        pday: *mut c_int,
+        psec: *mut c_int,
+        from: *const ffi::ASN1_TIME,
+        to: *const ffi::ASN1_TIME,
+    ) -> c_int;
+}
+
+// Seconds left until given time, negative if it has passed
+pub fn seconds_until(time: &Asn1TimeRef) -> Result<i64, Error> {
+    let mut days: c_int = 0;
+    let mut seconds: c_int = 0;
+    unsafe {
+        // Difference is counted from current time if "from" is null
+        if ASN1_TIME_diff(&mut days, &mut seconds, ptr::null(), time.as_ptr()) <= 0 {
+            return Err(Error::OpenSsl(ErrorStack::get()));
+        }
+    }
+    Ok(days as i64 * 86400 + seconds as i64)
+}
+
+// Seconds from one time to another, negative if "to" is earlier
+pub fn seconds_between(from: &Asn1TimeRef, to: &Asn1TimeRef) -> Result<i64, Error> {
+    let mut days: c_int = 0;
+    let mut seconds: c_int = 0;
+    unsafe {
+        if ASN1_TIME_diff(&mut days, &mut seconds, from.as_ptr(), to.as_ptr()) <= 0 {
+            return Err(Error::OpenSsl(ErrorStack::get()));
+        }
+    }
+    Ok(days as i64 * 86400 + seconds as i64)
+}
+
+// Current time moved by given number of seconds, Asn1Time::days_from_now has no finer granularity
+pub fn seconds_from_now(seconds: i64) -> Result<Asn1Time, Error> {
+    unsafe {
+        let time = ffi::X509_gmtime_adj(ptr::null_mut(), seconds as c_long);
+        if time.is_null() {
+            return Err(Error::OpenSsl(ErrorStack::get()));
+        }
+        Ok(Asn1Time::from_ptr(time))
+    }
+}
+
+// Generates positive 128 bit serial number
+pub fn random_serial() -> Result<BigNum, Error> {
+    let mut serial_number = BigNum::new()?;
+    serial_number.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    Ok(serial_number)
 }
 
 pub struct CertificateParameters<'a> {
+    pub key_algorithm: KeyAlgorithm,
     pub key_length: u32,
-    pub serial_number: u32,
+    // Random serial number is generated if not set
+    pub serial_number: Option<BigNum>,
     pub validity_days: u32,
+    // Validity in seconds, replaces validity_days and not_after if set
+    pub validity_seconds: Option<u32>,
+    // Seconds not_before is set back, so hosts with clocks slightly behind accept new certificate
+    pub not_before_skew: u32,
+    // Absolute validity period as "YYYYMMDDHHMMSSZ", relative one is used otherwise
+    pub not_before: Option<&'a str>,
+    pub not_after: Option<&'a str>,
+    // Certificate signed by CA expires no later than CA
+    pub cap_validity_at_ca: bool,
     pub subject: Subject<'a>,
     pub key_usage: Vec<&'a str>,
     pub extended_key_usage: Option<Vec<&'a str>>,
     pub basic_constraints: Option<Vec<&'a str>>,
     pub san: Option<Vec<&'a str>>,
     pub ca: Option<&'a Box<Bundle>>,
+    pub csr: Option<&'a X509ReqRef>,
+    // PEM encoded private key which is used instead of generating new one
+    pub key: Option<&'a [u8]>,
 }
 
 pub struct Subject<'a> {
     pub common_name: &'a str,                    // CN
     pub country: Option<&'a str>,                // C
-    pub organization: Option<&'a str>,           // O
-    pub organization_unit: Option<&'a str>,       // OU
+    pub organization: Vec<&'a str>,              // O, repeated for every value
+    pub organization_unit: Vec<&'a str>,         // OU, repeated for every value
     pub state_or_province_name: Option<&'a str>,  // ST
     pub locality: Option<&'a str>,               // L
 }
@@ -74,14 +270,20 @@ pub struct Subject<'a> {
 impl<'a> CertificateParameters<'a> {
     pub fn default(cn: &str) -> CertificateParameters {
         CertificateParameters {
+            key_algorithm: KeyAlgorithm::Rsa,
             key_length: 2048,
-            serial_number: 0,
+            serial_number: None,
             validity_days: 100,
+            validity_seconds: None,
+            not_before_skew: 0,
+            not_before: None,
+            not_after: None,
+            cap_validity_at_ca: true,
             subject: Subject {
                 common_name: &cn,
                 country: None,
-                organization: None,
-                organization_unit: None,
+                organization: vec![],
+                organization_unit: vec![],
                 state_or_province_name: None,
                 locality: None,
             },
@@ -90,19 +292,27 @@ impl<'a> CertificateParameters<'a> {
             basic_constraints: None,
             san: None,
             ca: None,
+            csr: None,
+            key: None,
         }
     }
 
     pub fn client(cn: &str, key_length: u32, validity_days: u32) -> CertificateParameters {
         CertificateParameters {
+            key_algorithm: KeyAlgorithm::Rsa,
             key_length: key_length,
-            serial_number: 0,
+            serial_number: None,
             validity_days: validity_days,
+            validity_seconds: None,
+            not_before_skew: 0,
+            not_before: None,
+            not_after: None,
+            cap_validity_at_ca: true,
             subject: Subject {
                 common_name: &cn,
                 country: None,
-                organization: None,
-                organization_unit: None,
+                organization: vec![],
+                organization_unit: vec![],
                 state_or_province_name: None,
                 locality: None,
             },
@@ -117,19 +327,27 @@ impl<'a> CertificateParameters<'a> {
             basic_constraints: None,
             san: None,
             ca: None,
+            csr: None,
+            key: None,
         }
     }
 
     pub fn server(cn: &str, key_length: u32, validity_days: u32) -> CertificateParameters {
         CertificateParameters {
+            key_algorithm: KeyAlgorithm::Rsa,
             key_length: key_length,
-            serial_number: 0,
+            serial_number: None,
             validity_days: validity_days,
+            validity_seconds: None,
+            not_before_skew: 0,
+            not_before: None,
+            not_after: None,
+            cap_validity_at_ca: true,
             subject: Subject {
                 common_name: &cn,
                 country: None,
-                organization: None,
-                organization_unit: None,
+                organization: vec![],
+                organization_unit: vec![],
                 state_or_province_name: None,
                 locality: None,
             },
@@ -144,19 +362,27 @@ impl<'a> CertificateParameters<'a> {
             basic_constraints: None,
             san: None,
             ca: None,
+            csr: None,
+            key: None,
         }
     }
 
     pub fn client_and_server(cn: &str, key_length: u32, validity_days: u32) -> CertificateParameters {
         CertificateParameters {
+            key_algorithm: KeyAlgorithm::Rsa,
             key_length: key_length,
-            serial_number: 0,
+            serial_number: None,
             validity_days: validity_days,
+            validity_seconds: None,
+            not_before_skew: 0,
+            not_before: None,
+            not_after: None,
+            cap_validity_at_ca: true,
             subject: Subject {
                 common_name: &cn,
                 country: None,
-                organization: None,
-                organization_unit: None,
+                organization: vec![],
+                organization_unit: vec![],
                 state_or_province_name: None,
                 locality: None,
             },
@@ -172,19 +398,27 @@ impl<'a> CertificateParameters<'a> {
             basic_constraints: None,
             san: None,
             ca: None,
+            csr: None,
+            key: None,
         }
     }
 
     pub fn ca(cn: &str, key_length: u32, validity_days: u32) -> CertificateParameters {
         CertificateParameters {
+            key_algorithm: KeyAlgorithm::Rsa,
             key_length: key_length,
-            serial_number: 0,
+            serial_number: None,
             validity_days: validity_days,
+            validity_seconds: None,
+            not_before_skew: 0,
+            not_before: None,
+            not_after: None,
+            cap_validity_at_ca: true,
             subject: Subject {
                 common_name: &cn,
                 country: None,
-                organization: None,
-                organization_unit: None,
+                organization: vec![],
+                organization_unit: vec![],
                 state_or_province_name: None,
                 locality: None,
             },
@@ -192,33 +426,210 @@ impl<'a> CertificateParameters<'a> {
                 "digital_signature",
                 "key_encipherment",
                 "key_cert_sign",
+                "crl_sign",
                 "critical",
             ],
             extended_key_usage: None,
             basic_constraints: Some(vec!["ca"]),
             san: None,
             ca: None,
+            csr: None,
+            key: None,
         }
     }
 
-    pub fn gen_cert(&self) -> Result<Box<Bundle>, &'static str> {
-       //Generate new key for cert
-        let rsa = Rsa::generate(self.key_length).unwrap();
-        let key = rsa.private_key_to_pem().unwrap();
-        let pkey = PKey::from_rsa(rsa).unwrap();
+    pub fn gen_cert(&self) -> Result<Box<Bundle>, Error> {
         // Create new certificate builder
-        let mut builder = X509::builder().unwrap();
-        builder.set_version(2).unwrap();
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
 
-        // Set public key for cert
-        builder.set_pubkey(&pkey).unwrap();
+        // Set public key for cert. It is taken from signing request or existing key if present,
+        // otherwise new key is generated.
+        let (pkey, key, key_id) = match self.csr {
+            Some(csr) => {
+                if self.key.is_some() {
+                    return Err(Error::Policy("Certificate signing request can't be used with existing key".to_owned()));
+                }
+                if self.ca.is_none() {
+                    return Err(Error::Policy("Certificate signing request can't be self signed".to_owned()));
+                }
+                let public_key = csr.public_key()?;
+                if !csr.verify(&public_key)? {
+                    return Err(Error::Policy("Certificate signing request has invalid signature".to_owned()));
+                }
+                builder.set_pubkey(&public_key)?;
+                (None, Vec::new(), public_key.id())
+            },
+            None => {
+                let (pkey, key) = match self.key {
+                    Some(key) => (load_private_key(&key)?, key.to_vec()),
+                    None => self.key_algorithm.generate(self.key_length)?,
+                };
+                builder.set_pubkey(&pkey)?;
+                let key_id = pkey.id();
+                (Some(pkey), key, key_id)
+            },
+        };
 
         // Set configuration method for cert
-        let conf = Conf::new(ConfMethod::default()).unwrap();
+        let conf = Conf::new(ConfMethod::default())?;
 
         // Add key usage into certificate
+        builder.append_extension(self.key_usage_extension(key_id)?)?;
+
+        //Add extended key usage to cert
+        if let Some(extended_usage) = self.extended_key_usage_extension()? {
+            builder.append_extension(extended_usage)?;
+        }
+
+        //Add subject to certificate
+        let name = self.subject_name()?;
+        builder.set_subject_name(&name)?;
+
+        //Set validity period for cert
+        let not_before = match self.not_before {
+            Some(time) => Asn1Time::from_str(&time)?,
+            None => seconds_from_now(-(self.not_before_skew as i64))?,
+        };
+        let not_after = match (self.validity_seconds, self.not_after) {
+            (Some(seconds), _) => seconds_from_now(seconds as i64)?,
+            (None, Some(time)) => Asn1Time::from_str(&time)?,
+            (None, None) => Asn1Time::days_from_now(self.validity_days)?,
+        };
+        let not_after: &Asn1TimeRef = match self.ca {
+            Some(ref ca) if self.cap_validity_at_ca && seconds_between(ca.cert.not_after(), &not_after)? > 0 => {
+                ca.cert.not_after()
+            }
+            _ => &not_after,
+        };
+        // Checked after cap, CA expiring before not_before would give certificate which is never valid
+        if seconds_between(&not_before, &not_after)? <= 0 {
+            return Err(Error::Policy("Certificate not_after must be later than not_before".to_owned()));
+        }
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+
+        // Set alternative names to cert
+        let san = match self.ca {
+            Some(ref ca) => self.san_extension(&builder.x509v3_context(Some(&ca.cert), Some(&conf)))?,
+            None => self.san_extension(&builder.x509v3_context(None, Some(&conf)))?,
+        };
+        if let Some(san) = san {
+            builder.append_extension(san)?;
+        }
+
+        // Set serial number
+        let serial_number = match self.serial_number {
+            Some(ref serial_number) => serial_number.to_asn1_integer()?,
+            None => random_serial()?.to_asn1_integer()?,
+        };
+        builder.set_serial_number(&serial_number)?;
+
+        // Set basic constraints
+        if let Some(constraints) = self.basic_constraints_extension()? {
+            builder.append_extension(constraints)?;
+        }
+
+        // Set key identifiers
+        let key_id = SubjectKeyIdentifier::new();
+        let mut  issuer_key_id = AuthorityKeyIdentifier::new();
+        issuer_key_id.keyid(true);
+        if let Some(ref ca) = self.ca {
+            let key_ext = key_id.build(&builder.x509v3_context(Some(&ca.cert), Some(&conf)))?;
+            let issuer_key_ext = issuer_key_id.build(&builder.x509v3_context(Some(&ca.cert), Some(&conf)))?;
+            builder.append_extension(issuer_key_ext)?;
+            builder.append_extension(key_ext)?;
+        } else {
+            let key_ext = key_id.build(&builder.x509v3_context(None, Some(&conf)))?;
+            // Because bug in rust-openssl. No method to set issuer key identifier if cert is self signed.
+            // let issuer_key_ext = issuer_key_id.build(&builder.x509v3_context(None, Some(&conf))).unwrap();
+            // builder.append_extension(issuer_key_ext).unwrap();
+            builder.append_extension(key_ext)?;
+        }
+
+        // Sign cert if it not self signed
+        if let Some(ref ca) = self.ca {
+            builder.set_issuer_name(&ca.cert.subject_name())?;
+            let ca_key = ca.private_key()?;
+            builder.sign(&ca_key, signing_digest(&ca_key))?;
+        } else if let Some(ref pkey) = pkey {
+            builder.set_issuer_name(&name)?;
+            builder.sign(&pkey, signing_digest(&pkey))?;
+        }
+
+        let cert: X509 = builder.build();
+
+        let bundle = Box::new(
+            Bundle{
+                cert,
+                key,
+        });
+        Ok(bundle)
+    }
+
+    // Generates new private key and certificate signing request for it.
+    // Request carries the same subject and extensions gen_cert would put into certificate,
+    // so it can be signed by CA which is kept elsewhere.
+    pub fn gen_csr(&self) -> Result<(X509Req, Vec<u8>), Error> {
+        let (pkey, key) = self.key_algorithm.generate(self.key_length)?;
+        let mut builder = X509Req::builder()?;
+        builder.set_version(0)?;
+        builder.set_pubkey(&pkey)?;
+        let name = self.subject_name()?;
+        builder.set_subject_name(&name)?;
+
+        let conf = Conf::new(ConfMethod::default())?;
+        let mut extensions = Stack::new()?;
+        extensions.push(self.key_usage_extension(pkey.id())?)?;
+        if let Some(extended_usage) = self.extended_key_usage_extension()? {
+            extensions.push(extended_usage)?;
+        }
+        if let Some(constraints) = self.basic_constraints_extension()? {
+            extensions.push(constraints)?;
+        }
+        if let Some(san) = self.san_extension(&builder.x509v3_context(Some(&conf)))? {
+            extensions.push(san)?;
+        }
+        builder.add_extensions(&extensions)?;
+
+        builder.sign(&pkey, signing_digest(&pkey))?;
+        Ok((builder.build(), key))
+    }
+
+    fn subject_name(&self) -> Result<X509Name, Error> {
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, &self.subject.common_name)?;
+        if let Some(ref value) = self.subject.country {
+            name.append_entry_by_nid(Nid::COUNTRYNAME, &value)?
+        };
+        for value in self.subject.organization.iter() {
+            name.append_entry_by_nid(Nid::ORGANIZATIONNAME, &value)?
+        }
+        for value in self.subject.organization_unit.iter() {
+            name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, &value)?
+        }
+        if let Some(ref value) = self.subject.state_or_province_name {
+            name.append_entry_by_nid(Nid::STATEORPROVINCENAME, &value)?
+        };
+        if let Some(ref value) = self.subject.locality {
+            name.append_entry_by_nid(Nid::LOCALITYNAME, &value)?
+        };
+        Ok(name.build())
+    }
+
+    // Key usage for key of given type. Key encipherment makes sense only for RSA keys
+    // and is forbidden for Ed25519 by RFC 8410, so it is dropped for other keys.
+    fn key_usage_for(&self, key_id: Id) -> Vec<&'a str> {
+        self.key_usage
+            .iter()
+            .cloned()
+            .filter(|&usage| usage != "key_encipherment" || key_id == Id::RSA)
+            .collect()
+    }
+
+    fn key_usage_extension(&self, key_id: Id) -> Result<X509Extension, Error> {
         let mut ku = KeyUsage::new();
-        for item in self.key_usage.iter() {
+        for item in self.key_usage_for(key_id).iter() {
             match item.as_ref() {
                 "critical" => ku.critical(),
                 "digital_signature" => ku.digital_signature(),
@@ -230,13 +641,13 @@ impl<'a> CertificateParameters<'a> {
                 "crl_sign" => ku.crl_sign(),
                 "encipher_only" => ku.encipher_only(),
                 "decipher_only" => ku.decipher_only(),
-                _ => &ku,
+                _ => return Err(Error::Policy(format!("Unknown key usage: {}", item))),
             };
         }
-        let usage: X509Extension = ku.build().unwrap();
-        builder.append_extension(usage).unwrap();
+        Ok(ku.build()?)
+    }
 
-        //Add extended key usage to cert
+    fn extended_key_usage_extension(&self) -> Result<Option<X509Extension>, Error> {
         if let Some(ref extended_usages) = self.extended_key_usage {
             let mut eku = ExtendedKeyUsage::new();
             for item in extended_usages.iter() {
@@ -246,144 +657,163 @@ impl<'a> CertificateParameters<'a> {
                     "client_auth" => eku.client_auth(),
                     "code_signing" => eku.code_signing(),
                     "time_stamping" => eku.time_stamping(),
-                    _ => &eku,
+                    _ => return Err(Error::Policy(format!("Unknown extended key usage: {}", item))),
                 };
             }
-            // let extended_usage: X509Extension = eku.build().unwrap();
-            let extended_usage = match eku.build() {
-                Ok(ex) => ex,
-                Err(err) => {
-                    println!("Error when build extended key usage:\n{}", err.to_string());
-                    return Err("Error!");
-                }
-            };
-            builder.append_extension(extended_usage).unwrap();
+            return Ok(Some(eku.build()?));
         }
+        Ok(None)
+    }
 
-        //Add subject to certificate
-        let mut name = X509Name::builder().unwrap();
-        name.append_entry_by_nid(Nid::COMMONNAME, &self.subject.common_name)
-            .unwrap();
-        if let Some(ref value) = self.subject.country {
-            name.append_entry_by_nid(Nid::COUNTRYNAME, &value)
-                .unwrap()
-        };
-        if let Some(ref value) = self.subject.organization {
-            name.append_entry_by_nid(Nid::ORGANIZATIONNAME, &value)
-                .unwrap()
-        };
-        if let Some(ref value) = self.subject.organization_unit {
-            name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, &value)
-                .unwrap()
-        };
-        if let Some(ref value) = self.subject.state_or_province_name {
-            name.append_entry_by_nid(Nid::STATEORPROVINCENAME, &value)
-                .unwrap()
-        };
-        if let Some(ref value) = self.subject.locality {
-            name.append_entry_by_nid(Nid::LOCALITYNAME, &value).unwrap()
-        };
-        let name = name.build();
-        builder.set_subject_name(&name).unwrap();
-
-        //Set validity period for cert
-        let not_before = Asn1Time::days_from_now(0).unwrap();
-        let not_after = Asn1Time::days_from_now(self.validity_days).unwrap();
-        builder.set_not_before(&not_before).unwrap();
-        builder.set_not_after(&not_after).unwrap();
+    fn basic_constraints_extension(&self) -> Result<Option<X509Extension>, Error> {
+        if let Some(ref constraints) = self.basic_constraints {
+            let mut bc = BasicConstraints::new();
+            bc.critical();
+            for constraint in constraints.iter() {
+                match constraint.as_ref() {
+                    "ca" => bc.ca(),
+                    _ => return Err(Error::Policy(format!("Unknown basic constraint: {}", constraint))),
+                };
+            }
+            return Ok(Some(bc.build()?));
+        }
+        Ok(None)
+    }
 
-        // Set alternative names to cert
+    fn san_extension(&self, context: &X509v3Context) -> Result<Option<X509Extension>, Error> {
         if let Some(ref alternative_names) = self.san {
             let mut san = SubjectAlternativeName::new();
             for name in alternative_names.iter() {
-                match is_ip(&name) {
-                    true => san.ip(&name),
-                    false => san.dns(&name),
+                match AltName::parse(&name)? {
+                    AltName::Ip(addr) => san.ip(&addr.to_string()),
+                    AltName::Dns(ref dns) => san.dns(&dns),
+                    AltName::Uri(ref uri) => san.uri(&uri),
+                    AltName::Email(ref email) => san.email(&email),
                 };
             }
-
-            if let Some(ref ca) = self.ca {
-                let san = san.build(&builder.x509v3_context(Some(&ca.cert), Some(&conf))).unwrap();
-                builder.append_extension(san).unwrap();
-            } else {
-                let san = san.build(&builder.x509v3_context(None, Some(&conf))).unwrap();
-                builder.append_extension(san).unwrap();
-            }
+            return Ok(Some(san.build(context)?));
         }
+        Ok(None)
+    }
+}
 
-        // Set serial number
-        let serial_number = BigNum::from_u32(self.serial_number).unwrap();
-        let serial_number = serial_number.as_ref().to_asn1_integer().unwrap();
-        builder.set_serial_number(&serial_number).unwrap();
+#[derive(Debug, PartialEq)]
+pub enum AltName {
+    Ip(IpAddr),
+    Dns(String),
+    Uri(String),
+    Email(String),
+}
 
-        // Set basic constraints
-        let mut bc = BasicConstraints::new();
-        bc.critical();
-        if let Some(ref constraints) = self.basic_constraints {
-            for constraint in constraints.iter() {
-                match constraint.as_ref() {
-                    "ca" => bc.ca(),
-                    _ => &bc,
-                };
-            }
-            let constraints: X509Extension = bc.build().unwrap();
-            builder.append_extension(constraints).unwrap();
+impl fmt::Display for AltName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AltName::Ip(ref ip) => write!(f, "IP:{}", ip),
+            AltName::Dns(ref dns) => write!(f, "DNS:{}", dns),
+            AltName::Uri(ref uri) => write!(f, "URI:{}", uri),
+            AltName::Email(ref email) => write!(f, "email:{}", email),
         }
+    }
+}
 
-        // Set key identifiers
-        let key_id = SubjectKeyIdentifier::new();
-        let mut  issuer_key_id = AuthorityKeyIdentifier::new();
-        issuer_key_id.keyid(true);
-        if let Some(ref ca) = self.ca {
-            let key_ext = key_id.build(&builder.x509v3_context(Some(&ca.cert), Some(&conf))).unwrap();
-            let issuer_key_ext = issuer_key_id.build(&builder.x509v3_context(Some(&ca.cert), Some(&conf))).unwrap();
-            builder.append_extension(issuer_key_ext).unwrap();
-            builder.append_extension(key_ext).unwrap();
-        } else {
-            let key_ext = key_id.build(&builder.x509v3_context(None, Some(&conf))).unwrap();
-            // Because bug in rust-openssl. No method to set issuer key identifier if cert is self signed.
-            // let issuer_key_ext = issuer_key_id.build(&builder.x509v3_context(None, Some(&conf))).unwrap();
-            // builder.append_extension(issuer_key_ext).unwrap();
-            builder.append_extension(key_ext).unwrap();
+impl AltName {
+    // Parses SAN entry from config. Type of entry can be forced with
+    // "ip:", "dns:", "uri:" or "email:" prefix. Without prefix IPv4 and IPv6
+    // addresses are recognized, everything else is a DNS name.
+    pub fn parse(entry: &str) -> Result<AltName, Error> {
+        if entry.starts_with("ip:") {
+            return match entry[3..].parse::<IpAddr>() {
+                Ok(addr) => Ok(AltName::Ip(addr)),
+                Err(err) => Err(Error::Config(format!("Invalid IP address in SAN \"{}\": {}", entry, err))),
+            };
         }
+        if entry.starts_with("dns:") {
+            return Ok(AltName::Dns(entry[4..].to_owned()));
+        }
+        if entry.starts_with("uri:") {
+            return Ok(AltName::Uri(entry[4..].to_owned()));
+        }
+        if entry.starts_with("email:") {
+            return Ok(AltName::Email(entry[6..].to_owned()));
+        }
+        match entry.parse::<IpAddr>() {
+            Ok(addr) => Ok(AltName::Ip(addr)),
+            Err(_) => Ok(AltName::Dns(entry.to_owned())),
+        }
+    }
 
-        // Sign cert if it not self signed
-        if let Some(ref ca) = self.ca {
-            builder.set_issuer_name(&ca.cert.subject_name()).unwrap();
-            builder.sign(&ca.private_key(), MessageDigest::sha256()).unwrap();
-        } else {
-            builder.sign(&pkey, MessageDigest::sha256()).unwrap();
-            builder.set_issuer_name(&name).unwrap();
+    // SAN entries of certificate, unsupported ones are skipped
+    pub fn from_cert(cert: &X509Ref) -> Vec<AltName> {
+        let mut alt_names = vec![];
+        if let Some(names) = cert.subject_alt_names() {
+            for name in names.iter() {
+                if let Some(dns) = name.dnsname() {
+                    alt_names.push(AltName::Dns(dns.to_owned()));
+                } else if let Some(uri) = name.uri() {
+                    alt_names.push(AltName::Uri(uri.to_owned()));
+                } else if let Some(email) = name.email() {
+                    alt_names.push(AltName::Email(email.to_owned()));
+                } else if let Some(ip) = name.ipaddress() {
+                    match ip.len() {
+                        4 => {
+                            let mut octets = [0u8; 4];
+                            octets.copy_from_slice(&ip);
+                            alt_names.push(AltName::Ip(IpAddr::from(octets)));
+                        }
+                        16 => {
+                            let mut octets = [0u8; 16];
+                            octets.copy_from_slice(&ip);
+                            alt_names.push(AltName::Ip(IpAddr::from(octets)));
+                        }
+                        _ => {}
+                    }
+                }
+            }
         }
+        alt_names
+    }
+}
 
-        let cert: X509 = builder.build();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::subject_dn;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
-        let bundle = Box::new(
-            Bundle{
-                cert,
-                key,
-        });
-        Ok(bundle)
+    #[test]
+    fn alt_name_without_prefix() {
+        assert_eq!(AltName::parse("10.0.0.1").unwrap(), AltName::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(AltName::parse("::1").unwrap(), AltName::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert_eq!(
+            AltName::parse("fd00::10").unwrap(),
+            AltName::Ip(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0x10)))
+        );
+        assert_eq!(AltName::parse("kubernetes").unwrap(), AltName::Dns("kubernetes".to_owned()));
+        assert_eq!(AltName::parse("10.0.0.256").unwrap(), AltName::Dns("10.0.0.256".to_owned()));
     }
-}
 
-// This function verifies is SAN an IP or hoatname
-fn is_ip(string: &str) -> bool {
-    let numbers: Vec<&str> = string.split(".").collect();
-    if numbers.len() != 4 {
-        return false;
+    #[test]
+    fn alt_name_with_prefix() {
+        assert_eq!(AltName::parse("ip:fe80::1").unwrap(), AltName::Ip("fe80::1".parse().unwrap()));
+        assert_eq!(AltName::parse("dns:10.0.0.1").unwrap(), AltName::Dns("10.0.0.1".to_owned()));
+        assert_eq!(
+            AltName::parse("uri:spiffe://cluster.local/ns/default").unwrap(),
+            AltName::Uri("spiffe://cluster.local/ns/default".to_owned())
+        );
+        assert_eq!(AltName::parse("email:admin@example.com").unwrap(), AltName::Email("admin@example.com".to_owned()));
+        assert!(AltName::parse("ip:example.com").is_err());
+        assert!(AltName::parse("ip:10.0.0.1/24").is_err());
     }
-    for octet in numbers {
-        match octet.parse::<u32>() {
-            Ok(num) => {
-                if num > 255 {
-                    return false;
-                };
-            }
-            Err(_) => {
-                return false;
-            }
+
+    #[test]
+    fn self_signed_cert() {
+        for &algorithm in [KeyAlgorithm::EcdsaP256, KeyAlgorithm::Ed25519].iter() {
+            let mut params = CertificateParameters::ca("test", 2048, 1);
+            params.key_algorithm = algorithm;
+            let bundle = params.gen_cert().unwrap();
+            let cert = X509::from_der(&bundle.cert.to_der().unwrap()).unwrap();
+            assert_eq!(subject_dn(cert.issuer_name()).unwrap(), "/CN=test");
+            assert!(cert.verify(&cert.public_key().unwrap()).unwrap());
         }
     }
-    true
 }
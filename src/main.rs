@@ -5,99 +5,54 @@ extern crate openssl;
 extern crate clap;
 
 // mod arg_parser;
-mod config_parser;
+mod apply;
+mod expiry;
+mod inventory;
 mod kubernetes_certs;
-mod kubeconfig;
+mod offline;
+mod renew;
+mod revocation;
+mod rotation;
+#[cfg(test)]
+mod testing;
+mod users;
+mod verify;
 
-use config_parser::User;
-use config_parser::Instance;
 use std::collections::HashMap;
-use std::io::Write;
-use std::os::unix::fs::symlink;
+use std::env;
 use std::process::exit;
-use std::fs::OpenOptions;
 use std::fs;
 use std::path::Path;
 use kubernetes_certs::gen_cert;
-use kubernetes_certs::CertType;
-use kubernetes_certs::gen_main_ca_cert;
-use cert_machine::Bundle;
-use kubernetes_certs::gen_ca_cert;
-use kubernetes_certs::write_bundle_to_file;
-use config_parser::Config;
-use clap::{App, Arg, SubCommand, AppSettings};
+use kubernetes_certs::gen_cert_reusing_key;
+use kubernetes_certs::sign_csr;
+use cert_machine::Authority;
+use cert_machine::CertType;
+use cert_machine::Config;
+use cert_machine::DryRunStore;
+use cert_machine::EncryptedStore;
+use cert_machine::Error;
+use cert_machine::FileStore;
+use cert_machine::init_pkcs11;
+use cert_machine::Passphrase;
+use cert_machine::Instance;
+use cert_machine::RevocationReason;
+use cert_machine::KeyEncryption;
+use cert_machine::Store;
+use cert_machine::User;
+use cert_machine::CA;
+use openssl::x509::X509Req;
+use cert_machine::store_bundle;
+use clap::{App, Arg, ArgMatches, SubCommand, AppSettings};
 
-pub struct CA {
-    pub main_ca: Box<Bundle>,
-    pub etcd_ca: Box<Bundle>,
-    pub front_ca: Box<Bundle>,
-}
-
-impl CA {
-    fn read_from_fs(dir: &str) -> CA {
-        let main_ca_dir = format!("{}/CA/root", &dir);
-        let etcd_ca_dir = format!("{}/CA/etcd", &dir);
-        let front_ca_dir = format!("{}/CA/front-proxy", &dir);
-        CA {
-            main_ca: Bundle::read_from_fs(&main_ca_dir, "ca").unwrap(),
-            etcd_ca: Bundle::read_from_fs(&etcd_ca_dir, "ca").unwrap(),
-            front_ca: Bundle::read_from_fs(&front_ca_dir, "ca").unwrap(),
-        }
-    }
-}
-
-fn create_ca(config: &Config) -> Result<CA, &'static str> {
+fn create_ca(store: &dyn Store, config: &Config) -> Result<CA, Error> {
     println!("Creating CA with name: {}", config.cluster_name);
-    let main_ca = match gen_main_ca_cert(&config) {
-        Ok(bundle) => {
-            let outdir = format!("{}/CA/root", &config.out_dir);
-            let index_filename = format!("{}/index", &outdir);
-            let mut file = OpenOptions::new().write(true)
-                                     .create_new(true)
-                                     .open(&index_filename)
-                                     .unwrap();
-            let sn: u32 = 0;
-            file.write_all(sn.to_string().as_bytes()).unwrap();
-            write_bundle_to_file(&bundle, &outdir, "ca", config.overwrite).unwrap();
-            bundle
-        },
-        Err(error) => return Err(error),
-    };
-
-    println!("Create CA: etcd");
-    let etcd_ca = match gen_ca_cert("etcd", Some(&main_ca), &config) {
-        Ok(bundle) => {
-            let outdir = format!("{}/CA/etcd", &config.out_dir);
-            let index_filename = format!("{}/index", &outdir);
-            let mut file = OpenOptions::new().write(true)
-                                     .create_new(true)
-                                     .open(&index_filename)
-                                     .unwrap();
-            let sn: u32 = 0;
-            file.write_all(sn.to_string().as_bytes()).unwrap();
-            write_bundle_to_file(&bundle, &outdir,"ca", config.overwrite).unwrap();
-            bundle
-            },
-        Err(error) => return Err(error),
-    };
-
-    println!("Create CA: front proxy");
-    let front_ca = match gen_ca_cert("front-proxy-ca", Some(&main_ca), &config) {
-        Ok(bundle) => {
-            let outdir = format!("{}/CA/front-proxy", &config.out_dir);
-            let index_filename = format!("{}/index", &outdir);
-            let mut file = OpenOptions::new().write(true)
-                                     .create_new(true)
-                                     .open(&index_filename)
-                                     .unwrap();
-            let sn: u32 = 0;
-            file.write_all(sn.to_string().as_bytes()).unwrap();
-            write_bundle_to_file(&bundle, &outdir, "ca", config.overwrite).unwrap();
-            bundle
-        },
-        Err(error) => return Err(error),
-
-    };
+    let ca = CA::generate(&config)?;
+    for authority in Authority::all() {
+        let outdir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+        create_index(store, &outdir)?;
+        store_bundle(store, ca.bundle(authority), &outdir, "ca", config.overwrite)?;
+    }
 
     let root_ca_crt_symlink = format!("{}/master/ca.crt", &config.out_dir);
     let root_ca_key_symlink = format!("{}/master/ca.key", &config.out_dir);
@@ -105,70 +60,60 @@ fn create_ca(config: &Config) -> Result<CA, &'static str> {
     let front_ca_crt_symlink = format!("{}/master/front-proxy-ca.crt", &config.out_dir);
     let front_ca_key_symlink = format!("{}/master/front-proxy-ca.key", &config.out_dir);
 
-    symlink("../CA/root/certs/ca.crt", &root_ca_crt_symlink).unwrap();
-    symlink("../CA/root/keys/ca.key", &root_ca_key_symlink).unwrap();
-    symlink("../CA/etcd/certs/ca.crt", &etcd_ca_crt_symlink).unwrap();
-    symlink("../CA/front-proxy/certs/ca.crt", &front_ca_crt_symlink).unwrap();
-    symlink("../CA/front-proxy/keys/ca.key", &front_ca_key_symlink).unwrap();
+    store.link("../CA/root/certs/ca.crt", &root_ca_crt_symlink)?;
+    store.link("../CA/etcd/certs/ca.crt", &etcd_ca_crt_symlink)?;
+    store.link("../CA/front-proxy/certs/ca.crt", &front_ca_crt_symlink)?;
+    // controller-manager and apiserver can't load encrypted keys, encryption at rest would be void
+    // with decrypted copies, so components get no CA keys then
+    match config.ca.encrypt_keys {
+        KeyEncryption::None => {
+            store.link("../CA/root/keys/ca.key", &root_ca_key_symlink)?;
+            store.link("../CA/front-proxy/keys/ca.key", &front_ca_key_symlink)?;
+        }
+        KeyEncryption::Ca | KeyEncryption::All => {
+            println!(
+                "CA keys are encrypted, {} and {} are not created",
+                &root_ca_key_symlink, &front_ca_key_symlink
+            );
+        }
+    }
 
-    Ok(CA {
-        main_ca,
-        etcd_ca,
-        front_ca,
-    })
+    Ok(ca)
 }
 
-fn create_cert_symlink(ca_dir: &str, cert_name: &str, dest: &str) {
-    let types = vec![("key", "keys"), ("crt", "certs")];
-    for postfix in types.iter() {
-        let source_filename = format!("{}/{}/{}.{}", &ca_dir, &postfix.1, &cert_name, &postfix.0);
-        let dest_filename = format!("{}.{}", &dest, &postfix.0);
-
-        if let Err(_) =  symlink(&source_filename, &dest_filename) {
-            match fs::symlink_metadata(&dest_filename) {
-                Ok(ref metadata) => {
-                    match metadata.file_type().is_symlink() {
-                        true => {
-                            fs::remove_file(&dest_filename).unwrap();
-                            symlink(&source_filename, &dest_filename).unwrap();
-                        },
-                        false => {
-                            eprintln!("Unable to create symlink. \"{}\" exists and not a symlink!", &dest_filename);
-                            exit(1);
-                        },
-                    }
-                },
-                Err(err) => {
-                    panic!("Unable to create symlink: {}", err);
-                },
-            }
-        }
+fn create_index(store: &dyn Store, ca_dir: &str) -> Result<(), Error> {
+    let index_filename = format!("{}/index", &ca_dir);
+    if store.exists(&index_filename) {
+        return Err(Error::Index(format!("Unable to create index file {}: already exists", &index_filename)));
     }
+    store.write(&index_filename, b"0")
 }
 
-fn create_symlink(source_pach: &str, symlink_path: &str) {
-    if let Err(err) =  symlink(&source_pach, &symlink_path) {
-        match fs::symlink_metadata(&symlink_path) {
-            Ok(ref metadata) => {
-                match metadata.file_type().is_symlink() {
-                    true => {
-                        fs::remove_file(&symlink_path).unwrap();
-                        symlink(&source_pach, &symlink_path).unwrap();
-                    },
-                    false => {
-                        eprintln!("Unable to create symlink. \"{}\": {}", &symlink_path, &err);
-                        exit(1);
-                    },
-                }
-            },
-            Err(err) => {
-                panic!("Unable to create symlink: {}", err);
-            },
-        }
+// Parses positive duration like "30d", "12h", "15m" or "60s" into seconds
+fn parse_duration(duration: &str) -> Result<i64, Error> {
+    let value = duration.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: i64 = match &duration[value.len()..] {
+        "d" => 86400,
+        "h" => 3600,
+        "m" => 60,
+        "s" => 1,
+        _ => 0,
+    };
+    let seconds = match value.parse::<i64>() {
+        Ok(value) if multiplier > 0 && value > 0 => value.checked_mul(multiplier),
+        _ => None,
+    };
+    match seconds {
+        Some(seconds) => Ok(seconds),
+        None => Err(Error::Config(format!(
+            "Invalid duration \"{}\", expected positive number with d, h, m or s suffix",
+            &duration
+        ))),
     }
 }
 
 fn main() {
+    let reason_names: Vec<&str> = RevocationReason::all().iter().map(|reason| reason.name()).collect();
     let matches = App::new("cert-machine")
         .about("TLS certificates generation tool for kubernetes")
         .version("1.0")
@@ -188,8 +133,19 @@ fn main() {
             .value_name("DIR")
             .help("Sets an output directory")
             .takes_value(true))
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Prints files to create, replace or symlink and certificates to issue without writing anything"))
         .subcommand(SubCommand::with_name("new")
             .about("Creates new CA and certificates"))
+        .subcommand(SubCommand::with_name("apply")
+            .about("Creates, reissues and revokes certificates to match config"))
+        .subcommand(SubCommand::with_name("gen-csr")
+            .about("Creates private keys and signing requests for all certificates to sign them by offline CA"))
+        .subcommand(SubCommand::with_name("import-signed")
+            .about("Imports certificates signed by offline CA")
+            .arg(Arg::with_name("dir")
+                .help("directory with signed certificates. Default: <outdir>/requests")))
         .subcommand(SubCommand::with_name("gen-cert")
             .about("Create new certificate for something")
             .arg(Arg::with_name("kind")
@@ -214,32 +170,203 @@ fn main() {
                 .required_if("kind", "etcd")
                 .help("name of instance or username"))
             .arg(Arg::with_name("group")
-                .help("group for user")))
+                .help("group for user"))
+            .arg(Arg::with_name("groups")
+                .long("group")
+                .value_name("GROUP")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Group for user, can be given several times")
+                .takes_value(true))
+            .arg(Arg::with_name("csr")
+                .long("csr")
+                .value_name("FILE")
+                .help("Sign certificate signing request instead of generating new key")
+                .takes_value(true))
+            .arg(Arg::with_name("reuse-key")
+                .long("reuse-key")
+                .conflicts_with("csr")
+                .help("Sign new certificate for private key currently in use")))
+        .subcommand(SubCommand::with_name("list")
+            .about("List all issued certificates")
+            .arg(Arg::with_name("output")
+                .long("output")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .help("Output format")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("check-expiry")
+            .about("Check expiry of certificates in use. Exits with 1 on warning and 2 on critical threshold")
+            .arg(Arg::with_name("warn")
+                .long("warn")
+                .value_name("DURATION")
+                .default_value("30d")
+                .help("Warning threshold, e.g. 30d or 12h")
+                .takes_value(true))
+            .arg(Arg::with_name("critical")
+                .long("critical")
+                .value_name("DURATION")
+                .default_value("7d")
+                .help("Critical threshold")
+                .takes_value(true))
+            .arg(Arg::with_name("textfile")
+                .long("textfile")
+                .value_name("FILE")
+                .help("Write expiry metrics for node_exporter textfile collector")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("user")
+            .about("Manage kubernetes users")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(SubCommand::with_name("issue")
+                .about("Issue short-lived user certificate and kubeconfig, issuance is recorded in CA/root/index.txt.audit")
+                .arg(Arg::with_name("name")
+                    .required(true)
+                    .help("username"))
+                .arg(Arg::with_name("groups")
+                    .long("group")
+                    .value_name("GROUP")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Group for user, can be given several times")
+                    .takes_value(true))
+                .arg(Arg::with_name("ttl")
+                    .long("ttl")
+                    .value_name("DURATION")
+                    .required(true)
+                    .help("Validity of certificate, e.g. 8h or 30m")
+                    .takes_value(true))
+                .arg(Arg::with_name("requester")
+                    .long("requester")
+                    .value_name("NAME")
+                    .help("Who requested access, recorded in audit log. Default: $USER")
+                    .takes_value(true))))
+        .subcommand(SubCommand::with_name("verify")
+            .alias("drift")
+            .about("Compare certificates in use with config. Exits with 1 if some of them differ"))
+        .subcommand(SubCommand::with_name("renew")
+            .about("Reissue certificates close to expiry")
+            .arg(Arg::with_name("within")
+                .long("within")
+                .value_name("DURATION")
+                .default_value("30d")
+                .help("Renew certificates which expire within this period, e.g. 30d or 12h")
+                .takes_value(true))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .help("Renew all certificates regardless of expiry"))
+            .arg(Arg::with_name("reuse-key")
+                .long("reuse-key")
+                .help("Sign new certificates for existing private keys")))
+        .subcommand(SubCommand::with_name("rotate-ca")
+            .about("Replace CA by successor. Old CA stays trusted until rotation is finalized")
+            .arg(Arg::with_name("ca")
+                .required(true)
+                .possible_values(&["root", "etcd", "front-proxy"])
+                .help("CA name"))
+            .arg(Arg::with_name("reissue")
+                .long("reissue")
+                .conflicts_with("finalize")
+                .help("Reissue certificates of CA being rotated by its successor"))
+            .arg(Arg::with_name("finalize")
+                .long("finalize")
+                .help("Drop old CA from trust bundles")))
+        .subcommand(SubCommand::with_name("revoke")
+            .about("Revoke certificate and regenerate CRL of its CA")
+            .arg(Arg::with_name("kind")
+                .required(true)
+                .possible_values(&["admin",
+                                   "apiserver",
+                                   "apiserver-client",
+                                   "apiserver-etcd-client",
+                                   "controller-manager",
+                                   "scheduler",
+                                   "front-proxy-client",
+                                   "proxy",
+                                   "kubelet",
+                                   "etcd",
+                                   "etcd-user",
+                                   "user"])
+                .help("kind of certificate"))
+            .arg(Arg::with_name("name")
+                .required_if("kind", "kubelet")
+                .required_if("kind", "etcd-user")
+                .required_if("kind", "user")
+                .required_if("kind", "etcd")
+                .help("name of instance or username"))
+            .arg(Arg::with_name("serial")
+                .long("serial")
+                .takes_value(true)
+                .help("Revoke only certificate with given serial number in hex"))
+            .arg(Arg::with_name("reason")
+                .long("reason")
+                .value_name("REASON")
+                .default_value("unspecified")
+                .possible_values(&reason_names)
+                .help("Revocation reason")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("gen-crl")
+            .about("Regenerate CRL of CA")
+            .arg(Arg::with_name("ca")
+                .possible_values(&["root", "etcd", "front-proxy"])
+                .help("CA name. Default: all")))
         .get_matches();
 
-        let config_filename = matches.value_of("config").unwrap();
-        let mut config = Config::new(&config_filename);
-        if let Some(opts_outdir) = matches.value_of("outdir") {
-            config.out_dir = opts_outdir.to_owned();
+    if let Err(err) = run(&matches) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let config_filename = matches.value_of("config").unwrap();
+    let mut config = Config::new(&config_filename)?;
+    if let Some(opts_outdir) = matches.value_of("outdir") {
+        config.out_dir = opts_outdir.to_owned();
+    }
+    let dry_run = matches.is_present("dry-run");
+    // Generating CA keys in token can't be planned, plan shows CA keys generated in memory instead
+    let generates_ca = match matches.subcommand() {
+        ("new", _) => true,
+        ("rotate-ca", Some(args)) => !args.is_present("reissue") && !args.is_present("finalize"),
+        ("apply", _) => !Path::new(&format!("{}/CA/root/certs/ca.crt", &config.out_dir)).exists(),
+        _ => false,
+    };
+    if dry_run && generates_ca && config.ca.pkcs11.as_ref().map_or(false, |pkcs11| pkcs11.generate) {
+        println!("CA keys would be generated in PKCS#11 token");
+        config.ca.pkcs11 = None;
+    }
+    let file_store = FileStore;
+    let passphrase = Passphrase::new(config.ca.passphrase_file.as_ref().map(|file| file.as_str()));
+    let encrypted_store = EncryptedStore::new(&file_store, config.ca.encrypt_keys, passphrase);
+    // Keys are planned unencrypted, passphrase is asked only to read CA keys
+    let dry_run_store = DryRunStore::new(&encrypted_store);
+    let store: &dyn Store = match dry_run {
+        true => &dry_run_store,
+        false => &encrypted_store,
+    };
+    // CA keys kept in token are needed only by commands which sign
+    if let Some(ref pkcs11) = config.ca.pkcs11 {
+        match matches.subcommand_name() {
+            Some("new") | Some("apply") | Some("gen-cert") | Some("user") | Some("renew") | Some("rotate-ca") | Some("revoke") | Some("gen-crl") => {
+                init_pkcs11(&pkcs11)?;
+            },
+            _ => {},
         }
+    }
 
     match matches.subcommand() {
         ("new", Some(_args)) => {
             let ca_dir = format!("{}/CA", &config.out_dir);
             if Path::new(&ca_dir).exists() {
-                eprintln!("CA alredy exists in directory: {}", &config.out_dir);
-                exit(1);
+                return Err(Error::Policy(format!("CA alredy exists in directory: {}", &config.out_dir)));
             }
-            kubernetes_certs::create_directory_struct(&config, &config.out_dir).unwrap();
+            kubernetes_certs::create_directory_struct(store, &config, &config.out_dir)?;
 
-            let ca = match create_ca(&config) {
-                Ok(ca) => ca,
-                Err(err) => {
-                    panic!("Error when creating certificate authority: {}", err);
-                },
-            };
+            let ca = create_ca(store, &config)?;
+            revocation::write_crls(store, &config, &ca)?;
 
-            kubernetes_certs::kube_certs(&ca, &config, &config.out_dir);
+            kubernetes_certs::kube_certs(store, &ca, &config, &config.out_dir)?;
 
             for instance in config.worker.iter() {
                 let mut cert_filename = match instance.filename {
@@ -247,9 +374,9 @@ fn main() {
                     None => instance.hostname.clone(),
                 };
                 let ca_symlink = format!("{}/{}/ca.crt", &config.out_dir, &cert_filename);
-                symlink("../CA/root/certs/ca.crt", &ca_symlink).unwrap();
-                gen_cert(&ca, &config, &CertType::Kubelet(&instance)).unwrap();
-                gen_cert(&ca, &config, &CertType::KubeletServer(&instance)).unwrap();
+                store.link("../CA/root/certs/ca.crt", &ca_symlink)?;
+                gen_cert(store, &ca, &config, &CertType::Kubelet(&instance))?;
+                gen_cert(store, &ca, &config, &CertType::KubeletServer(&instance))?;
             }
 
             for instance in config.etcd_server.iter() {
@@ -258,56 +385,78 @@ fn main() {
                     None => instance.hostname.clone(),
                 };
                 let ca_symlink = format!("{}/{}/etcd-ca.crt", &config.out_dir, &cert_filename);
-                symlink("../CA/etcd/certs/ca.crt", &ca_symlink).unwrap();
+                store.link("../CA/etcd/certs/ca.crt", &ca_symlink)?;
 
-                gen_cert(&ca, &config, &CertType::EtcdServer(&instance)).unwrap();
+                gen_cert(store, &ca, &config, &CertType::EtcdServer(&instance))?;
             }
             if let Some(ref users) = config.user {
                 for user in users {
                     println!("Creating cert for kubernetes user: {}", &user.username);
-                    gen_cert(&ca, &config, &CertType::User(&user)).unwrap();
+                    gen_cert(store, &ca, &config, &CertType::User(&user))?;
                 }
             }
             if let Some(ref users) = config.etcd_users {
                 for user in users {
                     println!("Creating cert for etcd user: {}", &user);
-                    gen_cert(&ca, &config, &CertType::EtcdUser(&user)).unwrap();
+                    gen_cert(store, &ca, &config, &CertType::EtcdUser(&user))?;
                 }
             }
+            apply::save_applied(store, &config)?;
+        },
+        ("apply", Some(_args)) => {
+            apply::apply(store, &config)?;
+        },
+        ("gen-csr", Some(_args)) => {
+            offline::gen_requests(store, &config)?;
+        },
+        ("import-signed", Some(args)) => {
+            let requests_dir = format!("{}/requests", &config.out_dir);
+            let signed_dir = args.value_of("dir").unwrap_or(&requests_dir);
+            offline::import_signed(store, &config, &signed_dir)?;
         },
         ("gen-cert", Some(args)) =>{
-            let ca = CA::read_from_fs(&config.out_dir);
+            let ca = CA::read(store, &config.out_dir)?;
+            let csr = match args.value_of("csr") {
+                Some(csr_filename) => Some(X509Req::from_pem(&fs::read(&csr_filename)?)?),
+                None => None,
+            };
+            let reuse_key = args.is_present("reuse-key");
+            let issue = |cert_type: &CertType| match csr {
+                Some(ref csr) => sign_csr(store, &ca, &config, &cert_type, &csr),
+                None if reuse_key => gen_cert_reusing_key(store, &ca, &config, &cert_type),
+                None => gen_cert(store, &ca, &config, &cert_type),
+            };
             match args.value_of("kind").unwrap() {
                 "admin" => {
-                    gen_cert(&ca, &config, &CertType::Admin).unwrap();
+                    issue(&CertType::Admin)?;
                     ()
                 },
                 "apiserver" => {
-                    gen_cert(&ca, &config, &CertType::ApiServer).unwrap();
+                    issue(&CertType::ApiServer)?;
                     ()
                 },
                 "apiserver-client" => {
-                    gen_cert(&ca, &config, &CertType::ApiServerClient).unwrap();
+                    issue(&CertType::ApiServerClient)?;
                     ()
                 },
                 "apiserver-etcd-client" => {
-                    gen_cert(&ca, &config, &CertType::ApiServerEtcdClient).unwrap();
+                    issue(&CertType::ApiServerEtcdClient)?;
                     ()
                 },
                 "controller-manager" => {
-                    gen_cert(&ca, &config, &CertType::ControllerManager).unwrap();
+                    issue(&CertType::ControllerManager)?;
                     ()
                 },
                 "scheduler" => {
-                    gen_cert(&ca, &config, &CertType::Scheduler).unwrap();
+                    issue(&CertType::Scheduler)?;
                     ()
                 },
                 "front-proxy-client" => {
-                    gen_cert(&ca, &config, &CertType::FrontProxy).unwrap();
+                    issue(&CertType::FrontProxy)?;
                     ()
                 },
                 "proxy" => {
-                    gen_cert(&ca, &config, &CertType::Proxy).unwrap();
+                    issue(&CertType::Proxy)?;
                     ()
                 },
                 "kubelet" => {
@@ -321,8 +470,7 @@ fn main() {
                     let instance = match instances.get::<str>(&hostname) {
                         Some(instance) => instance,
                         None => {
-                            eprintln!("No such kubelet hostname found in config file: {}", &hostname);
-                            exit(1);
+                            return Err(Error::Config(format!("No such kubelet hostname found in config file: {}", &hostname)));
                         },
                     };
                     let mut cert_filename = match instance.filename {
@@ -330,12 +478,11 @@ fn main() {
                         None => instance.hostname.clone(),
                     };
                     let node_path = format!("{}/{}", &config.out_dir, &cert_filename);
-                    fs::create_dir_all(&node_path).unwrap();
-                    let ca_cert_path = format!("../CA/root/certs/ca.crt");
+                    let ca_cert_path = format!("../{}", Authority::Root.trust_file(store, &config.out_dir));
                     let ca_cert_symlink = format!("{}/ca.crt", &node_path);
-                    create_symlink(&ca_cert_path, &ca_cert_symlink);
-                    gen_cert(&ca, &config, &CertType::Kubelet(&instance)).unwrap();
-                    gen_cert(&ca, &config, &CertType::KubeletServer(&instance)).unwrap();
+                    store.link(&ca_cert_path, &ca_cert_symlink)?;
+                    issue(&CertType::Kubelet(&instance))?;
+                    issue(&CertType::KubeletServer(&instance))?;
                     ()
                 },
                 "etcd" => {
@@ -348,8 +495,7 @@ fn main() {
                     let instance = match instances.get::<str>(&hostname) {
                         Some(instance) => instance,
                         None => {
-                            eprintln!("No such etcd server hostname found in config file: \"{}\"", &hostname);
-                            exit(1);
+                            return Err(Error::Config(format!("No such etcd server hostname found in config file: \"{}\"", &hostname)));
                         },
                     };
                     let dirname = match instance.filename {
@@ -357,42 +503,116 @@ fn main() {
                         None => instance.hostname.clone(),
                     };
                     let node_path = format!("{}/{}", &config.out_dir, &dirname);
-                    fs::create_dir_all(&node_path).unwrap();
-                    let ca_cert_path = format!("../CA/etcd/certs/ca.crt");
+                    let ca_cert_path = format!("../{}", Authority::Etcd.trust_file(store, &config.out_dir));
                     let ca_cert_symlink = format!("{}/etcd-ca.crt", &node_path);
-                    create_symlink(&ca_cert_path, &ca_cert_symlink);
+                    store.link(&ca_cert_path, &ca_cert_symlink)?;
                     println!("Gen cert for \"{}\" etcd node!", hostname);
-                    gen_cert(&ca, &config, &CertType::EtcdServer(&instance)).unwrap();
+                    issue(&CertType::EtcdServer(&instance))?;
                     ()
                 },
                 "etcd-user" => {
                     let username = args.value_of("name").unwrap();
                     println!("Gen cert for \"{}\" etcd user!", username);
-                    gen_cert(&ca, &config, &CertType::EtcdUser(&username)).unwrap();
+                    issue(&CertType::EtcdUser(&username))?;
                     ()
                 },
                 "user" => {
                     let username = args.value_of("name").unwrap();
-                    let mut group: Option<String> = None;
-                    // let group = args.value_of("group").to_owned();
-                    print!("Create user cert with name: {}", &username);
-                    let ca = CA::read_from_fs(&config.out_dir);
-                    match args.value_of("group") {
-                        Some(group_name) => {
-                            println!(" and group: {}", &group_name);
-                            group = Some(group_name.to_owned());
-                        },
-                        None => print!("\n"),
+                    let mut groups: Vec<String> = vec![];
+                    groups.extend(args.value_of("group").map(|group| group.to_owned()));
+                    groups.extend(args.values_of("groups").into_iter().flat_map(|values| values.map(|group| group.to_owned())));
+                    match groups.is_empty() {
+                        false => println!("Create user cert with name: {} and groups: {}", &username, groups.join(", ")),
+                        true => println!("Create user cert with name: {}", &username),
                     }
-                    let user = User {
-                        username: username.to_string(),
-                        group: group,
-                    };
-                    gen_cert(&ca, &config, &CertType::User(&user)).unwrap();
+                    let user = User::new(&username, groups);
+                    issue(&CertType::User(&user))?;
                 },
                 _ => eprintln!("Error!"),
             }
         },
+        ("list", Some(args)) => {
+            inventory::list(store, &config, args.value_of("output") == Some("json"))?;
+        },
+        ("check-expiry", Some(args)) => {
+            let warn = parse_duration(args.value_of("warn").unwrap())?;
+            let critical = parse_duration(args.value_of("critical").unwrap())?;
+            let status = expiry::check_expiry(store, &config, warn, critical, args.value_of("textfile"))?;
+            if status != 0 {
+                exit(status);
+            }
+        },
+        ("user", Some(args)) => {
+            if let ("issue", Some(args)) = args.subcommand() {
+                let ca = CA::read(store, &config.out_dir)?;
+                let groups = args.values_of("groups").into_iter().flat_map(|values| values.map(|group| group.to_owned())).collect();
+                let user = User::new(args.value_of("name").unwrap(), groups);
+                let ttl = parse_duration(args.value_of("ttl").unwrap())?;
+                let requester = match args.value_of("requester") {
+                    Some(requester) => requester.to_owned(),
+                    None => env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+                };
+                users::issue_user(store, &ca, &config, &user, ttl, &requester)?;
+            }
+        },
+        ("verify", Some(_args)) => {
+            if verify::verify(store, &config)? > 0 {
+                exit(1);
+            }
+        },
+        ("renew", Some(args)) => {
+            let ca = CA::read(store, &config.out_dir)?;
+            let within = parse_duration(args.value_of("within").unwrap())?;
+            renew::renew(store, &config, &ca, within, args.is_present("all"), args.is_present("reuse-key"))?;
+        },
+        ("rotate-ca", Some(args)) => {
+            let ca_name = args.value_of("ca").unwrap();
+            let authority = Authority::all().into_iter().find(|a| a.dir_name() == ca_name).unwrap();
+            if args.is_present("reissue") {
+                rotation::reissue_certs(store, &config, authority)?;
+            } else if args.is_present("finalize") {
+                rotation::finalize_rotation(store, &config, authority)?;
+            } else {
+                rotation::rotate_ca(store, &config, authority)?;
+            }
+        },
+        ("revoke", Some(args)) => {
+            let ca = CA::read(store, &config.out_dir)?;
+            let reason = RevocationReason::from_name(args.value_of("reason").unwrap()).unwrap();
+            let kind = args.value_of("kind").unwrap();
+            revocation::revoke(store, &config, &ca, kind, args.value_of("name"), args.value_of("serial"), reason)?;
+        },
+        ("gen-crl", Some(args)) => {
+            let ca = CA::read(store, &config.out_dir)?;
+            match args.value_of("ca") {
+                Some(ca_name) => {
+                    let authority = Authority::all().into_iter().find(|a| a.dir_name() == ca_name).unwrap();
+                    revocation::write_crl(store, &config, ca.bundle(authority), authority)?;
+                },
+                None => revocation::write_crls(store, &config, &ca)?,
+            }
+        },
         _ => unreachable!(),
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+
+    #[test]
+    fn duration_units() {
+        assert_eq!(parse_duration("30d").unwrap(), 30 * 86400);
+        assert_eq!(parse_duration("12h").unwrap(), 12 * 3600);
+        assert_eq!(parse_duration("15m").unwrap(), 15 * 60);
+        assert_eq!(parse_duration("60s").unwrap(), 60);
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for duration in &["", "30", "d", "30w", "1.5d", "-5d", "0h", "999999999999999999d"] {
+            assert!(parse_duration(duration).is_err(), "{} should be rejected", duration);
+        }
+    }
 }
@@ -0,0 +1,147 @@
+use cert_machine::{
+    all_cert_types, install_cert, link_ca_certs, profile, Authority, Bundle, Config, Database, Entry, Error, Store,
+};
+use kubernetes_certs::{create_directory_struct, gen_service_account_keys};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+
+// Generates private keys and signing requests for all certificates defined in config.
+// Request "<out_dir>/requests/<ca>/<name>.csr" should be signed by CA "<ca>",
+// signed certificate is expected as "<ca>/<name>.crt" by import_signed.
+pub fn gen_requests(store: &dyn Store, config: &Config) -> Result<(), Error> {
+    let requests_dir = format!("{}/requests", &config.out_dir);
+    if store.exists(&requests_dir) {
+        return Err(Error::Policy(format!("Signing requests already exist in directory: {}", &requests_dir)));
+    }
+    create_directory_struct(store, &config, &config.out_dir)?;
+    for authority in Authority::all() {
+        store.create_dir(&format!("{}/{}", &requests_dir, authority.dir_name()))?;
+    }
+
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        println!("Creating signing request for {}", &profile.description);
+        let request_filename = format!("{}/{}/{}", &requests_dir, profile.authority.dir_name(), cert_type.name());
+        let (csr, key) = profile.params().gen_csr()?;
+        store.write(&format!("{}.csr", &request_filename), &csr.to_pem()?)?;
+        store.write(&format!("{}.key", &request_filename), &key)?;
+    }
+    gen_service_account_keys(store, &config, &config.out_dir)
+}
+
+// Places certificates signed by offline CA into output directory.
+// CA certificates are taken from "<signed_dir>/<ca>/ca.crt" if present.
+pub fn import_signed(store: &dyn Store, config: &Config, signed_dir: &str) -> Result<(), Error> {
+    for authority in Authority::all() {
+        let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+        let ca_cert_filename = format!("{}/{}/ca.crt", &signed_dir, authority.dir_name());
+        if store.exists(&ca_cert_filename) {
+            println!("Importing CA certificate: {}", &ca_cert_filename);
+            let cert = X509::from_pem(&store.read(&ca_cert_filename)?)?;
+            store.write(&format!("{}/certs/ca.crt", &ca_dir), &cert.to_pem()?)?;
+            // Recorded once, import is repeated until all certificates are signed
+            let mut database = Database::open(store, &ca_dir)?;
+            if database.find(&*cert.serial_number().to_bn()?)?.is_none() {
+                database.append(store, Entry::from_cert(&cert, "certs/ca.crt")?)?;
+            }
+        }
+        let index_filename = format!("{}/index", &ca_dir);
+        if !store.exists(&index_filename) {
+            store.write(&index_filename, b"0")?;
+        }
+    }
+    link_ca_certs(store, &config)?;
+
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        let ca_name = profile.authority.dir_name();
+        let request_filename = format!("{}/requests/{}/{}", &config.out_dir, ca_name, cert_type.name());
+        let key_filename = format!("{}.key", &request_filename);
+        let signed_filename = format!("{}/{}/{}.crt", &signed_dir, ca_name, cert_type.name());
+
+        // Already imported
+        if !store.exists(&key_filename) {
+            continue;
+        }
+        if !store.exists(&signed_filename) {
+            println!("Waiting for signed certificate: {}", &signed_filename);
+            continue;
+        }
+
+        let cert = X509::from_pem(&store.read(&signed_filename)?)?;
+        let key = store.read(&key_filename)?;
+        let private_key = PKey::private_key_from_pem(&key)?;
+        if !cert.public_key()?.public_eq(&private_key) {
+            return Err(Error::Policy(format!(
+                "{} doesn't match private key of signing request",
+                &signed_filename
+            )));
+        }
+        let ca_cert = store.read_cert(&profile.ca_dir(), "ca")?;
+        let ca_public_key = ca_cert.cert.public_key()?;
+        if !cert.verify(&ca_public_key)? {
+            return Err(Error::Policy(format!("{} isn't signed by {} CA", &signed_filename, ca_name)));
+        }
+
+        println!("Importing cert for {}", &profile.description);
+        install_cert(store, &profile, &Box::new(Bundle { cert, key }))?;
+        store.remove(&key_filename)?;
+        store.remove(&format!("{}.csr", &request_filename))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::MemoryStore;
+    use create_ca;
+    use kubernetes_certs::sign_csr;
+    use openssl::x509::X509Req;
+    use testing;
+
+    #[test]
+    fn import_signed_by_offline_ca() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let store = MemoryStore::new();
+        gen_requests(&store, &config).unwrap();
+
+        // Offline CA keeps its own store, only certificates are brought back
+        let offline_store = MemoryStore::new();
+        let ca = create_ca(&offline_store, &config).unwrap();
+        for authority in Authority::all() {
+            let ca_cert = ca.bundle(authority).cert.to_pem().unwrap();
+            store.write(&format!("signed/{}/ca.crt", authority.dir_name()), &ca_cert).unwrap();
+        }
+        for cert_type in all_cert_types(&config) {
+            let ca_name = profile(&config, &cert_type).authority.dir_name();
+            let request_filename = format!("out/requests/{}/{}.csr", ca_name, cert_type.name());
+            let csr = X509Req::from_pem(&store.read(&request_filename).unwrap()).unwrap();
+            let bundle = sign_csr(&offline_store, &ca, &config, &cert_type, &csr).unwrap();
+            let signed_filename = format!("signed/{}/{}.crt", ca_name, cert_type.name());
+            store.write(&signed_filename, &bundle.cert.to_pem().unwrap()).unwrap();
+        }
+
+        import_signed(&store, &config, "signed").unwrap();
+        import_signed(&store, &config, "signed").unwrap();
+        for authority in Authority::all() {
+            let ca_dir = format!("out/CA/{}", authority.dir_name());
+            let database = Database::open(&store, &ca_dir).unwrap();
+            let ca_serial = ca.bundle(authority).cert.serial_number().to_bn().unwrap();
+            let ca_entries = database.entries.iter().filter(|entry| entry.filename == "certs/ca.crt");
+            assert_eq!(ca_entries.count(), 1);
+            assert!(database.find(&ca_serial).unwrap().is_some());
+            let signed = all_cert_types(&config)
+                .iter()
+                .filter(|cert_type| profile(&config, &cert_type).authority == authority)
+                .count();
+            assert_eq!(database.entries.len(), signed + 1);
+        }
+        assert!(store.list("out/requests").unwrap().is_empty());
+
+        let cert = X509::from_pem(&store.read("out/master/apiserver.crt").unwrap()).unwrap();
+        let key = PKey::private_key_from_pem(&store.read("out/master/apiserver.key").unwrap()).unwrap();
+        assert!(cert.public_key().unwrap().public_eq(&key));
+        assert!(cert.verify(&ca.bundle(Authority::Root).cert.public_key().unwrap()).unwrap());
+    }
+}
@@ -0,0 +1,97 @@
+use cert_machine::{all_cert_types, current_cert, profile, seconds_until, Config, Error, Store, CA};
+use kubernetes_certs::{gen_cert_reusing_key, reissue_cert};
+
+// Reissues every leaf certificate defined in config which expires within given number of seconds.
+// Symlinks and kubeconfigs are switched to new certificates by install_cert.
+// Certificates which were never issued or are revoked are only reported, gen-cert or apply issue them.
+pub fn renew(
+    store: &dyn Store,
+    config: &Config,
+    ca: &CA,
+    within: i64,
+    all: bool,
+    reuse_key: bool,
+) -> Result<(), Error> {
+    let mut renewed = 0;
+    let mut missing = 0;
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        match current_cert(store, &profile)? {
+            Some(bundle) => {
+                let seconds_remaining = seconds_until(bundle.cert.not_after())?;
+                if !all && seconds_remaining >= within {
+                    continue;
+                }
+                println!(
+                    "Renewing cert for {}: expires in {} days",
+                    &profile.description,
+                    seconds_remaining / 86400
+                );
+            }
+            None => {
+                println!("No valid cert found for {}, skipping", &profile.description);
+                missing += 1;
+                continue;
+            }
+        }
+        match reuse_key {
+            true => gen_cert_reusing_key(store, &ca, &config, &cert_type)?,
+            false => reissue_cert(store, &ca, &config, &cert_type, None)?,
+        };
+        renewed += 1;
+    }
+    println!("Renewed {} certificates", renewed);
+    if missing > 0 {
+        println!("{} certificates have no valid cert to renew, issue them with gen-cert or apply", missing);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::{issue_profile, serial_hex, CertType, MemoryStore};
+    use create_ca;
+    use testing;
+
+    // Serial of current certificate by profile description
+    fn current_serials(store: &dyn Store, config: &Config) -> Vec<(String, String)> {
+        let mut serials = vec![];
+        for cert_type in all_cert_types(&config) {
+            let profile = profile(&config, &cert_type);
+            let bundle = current_cert(store, &profile).unwrap().unwrap();
+            let serial = serial_hex(&bundle.cert.serial_number().to_bn().unwrap()).unwrap();
+            serials.push((profile.description, serial));
+        }
+        serials
+    }
+
+    #[test]
+    fn renews_expiring_certs() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let store = MemoryStore::new();
+        let ca = create_ca(&store, &config).unwrap();
+        for cert_type in all_cert_types(&config) {
+            let mut profile = profile(&config, &cert_type);
+            if let CertType::ApiServer = cert_type {
+                profile.validity_seconds = Some(3600);
+            }
+            issue_profile(&store, &ca, &profile, None, None).unwrap();
+        }
+        let issued = current_serials(&store, &config);
+
+        renew(&store, &config, &ca, 86400, false, false).unwrap();
+        let renewed = current_serials(&store, &config);
+        let apiserver = profile(&config, &CertType::ApiServer);
+        for (before, after) in issued.iter().zip(renewed.iter()) {
+            assert_eq!(before.1 != after.1, before.0 == apiserver.description, "{}", &before.0);
+        }
+        let bundle = current_cert(&store, &apiserver).unwrap().unwrap();
+        assert!(seconds_until(bundle.cert.not_after()).unwrap() > 86400);
+
+        renew(&store, &config, &ca, 86400, true, true).unwrap();
+        for (before, after) in renewed.iter().zip(current_serials(&store, &config).iter()) {
+            assert_ne!(before.1, after.1, "{}", &before.0);
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use cert_machine::{
+    gen_crl, is_cert_of, profile, serial_hex, Authority, Bundle, CertType, Config, Database, Error, Instance,
+    RevocationReason, Status, Store, User, CA,
+};
+use openssl::bn::BigNum;
+
+// CA which issues certificates of given kind
+pub fn kind_authority(kind: &str) -> Authority {
+    match kind {
+        "etcd" | "etcd-user" | "apiserver-etcd-client" => Authority::Etcd,
+        "front-proxy-client" => Authority::FrontProxy,
+        _ => Authority::Root,
+    }
+}
+
+// Revokes certificate of given kind and name with given serial number or every valid one of them.
+// CRL of CA is regenerated afterwards.
+pub fn revoke(
+    store: &dyn Store,
+    config: &Config,
+    ca: &CA,
+    kind: &str,
+    name: Option<&str>,
+    serial: Option<&str>,
+    reason: RevocationReason,
+) -> Result<(), Error> {
+    let authority = kind_authority(&kind);
+    let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+    let mut database = Database::open(store, &ca_dir)?;
+    let filenames = kind_filenames(&config, &kind, name)?;
+
+    let mut serials = vec![];
+    match serial {
+        Some(serial) => {
+            let serial = match BigNum::from_hex_str(&serial) {
+                Ok(serial) => serial,
+                Err(_) => return Err(Error::Config(format!("Invalid serial number: {}", &serial))),
+            };
+            // Serial number alone could point to certificate of other kind or even to CA itself
+            match database.find(&serial)? {
+                Some(entry) if filenames.iter().any(|filename| is_cert_of(&entry.filename, &filename)) => {
+                    serials.push(serial)
+                }
+                Some(entry) => {
+                    return Err(Error::Policy(format!(
+                        "Certificate {} is not {} certificate of {}: {}",
+                        &entry.serial,
+                        &kind,
+                        name.unwrap_or(&kind),
+                        &entry.filename
+                    )));
+                }
+                None => {
+                    return Err(Error::Index(format!(
+                        "Certificate {} not found in {}/index.txt",
+                        serial_hex(&serial)?,
+                        &ca_dir
+                    )));
+                }
+            }
+        }
+        None => {
+            for entry in database.entries.iter().filter(|entry| entry.status == Status::Valid) {
+                if filenames.iter().any(|filename| is_cert_of(&entry.filename, &filename)) {
+                    serials.push(BigNum::from_hex_str(&entry.serial)?);
+                }
+            }
+        }
+    }
+    if serials.is_empty() {
+        return Err(Error::Policy(format!(
+            "No valid {} certificates found for: {}",
+            &kind,
+            name.unwrap_or(&kind)
+        )));
+    }
+
+    for serial in serials.iter() {
+        database.revoke(store, &serial, reason)?;
+        println!("Revoked certificate {} ({})", serial_hex(&serial)?, reason.name());
+    }
+    write_crl(store, &config, ca.bundle(authority), authority)
+}
+
+// File names of certificates which are issued by gen-cert for given kind
+fn kind_filenames(config: &Config, kind: &str, name: Option<&str>) -> Result<Vec<String>, Error> {
+    let name = name.unwrap_or("");
+    let user = User::new(&name, vec![]);
+    let cert_types = match kind {
+        "admin" => vec![CertType::Admin],
+        "apiserver" => vec![CertType::ApiServer],
+        "apiserver-client" => vec![CertType::ApiServerClient],
+        "apiserver-etcd-client" => vec![CertType::ApiServerEtcdClient],
+        "controller-manager" => vec![CertType::ControllerManager],
+        "scheduler" => vec![CertType::Scheduler],
+        "front-proxy-client" => vec![CertType::FrontProxy],
+        "proxy" => vec![CertType::Proxy],
+        "kubelet" => {
+            let instance = find_instance(&config.worker, &kind, &name)?;
+            vec![CertType::Kubelet(&instance), CertType::KubeletServer(&instance)]
+        }
+        "etcd" => vec![CertType::EtcdServer(find_instance(&config.etcd_server, &kind, &name)?)],
+        "etcd-user" => vec![CertType::EtcdUser(&name)],
+        "user" => vec![CertType::User(&user)],
+        _ => return Err(Error::Policy(format!("Unknown kind of certificate: {}", &kind))),
+    };
+    Ok(cert_types.iter().map(|cert_type| profile(&config, &cert_type).filename).collect())
+}
+
+fn find_instance<'a>(instances: &'a [Instance], kind: &str, hostname: &str) -> Result<&'a Instance, Error> {
+    match instances.iter().find(|instance| instance.hostname == hostname) {
+        Some(instance) => Ok(instance),
+        None => Err(Error::Config(format!(
+            "No such {} hostname found in config file: {}",
+            &kind, &hostname
+        ))),
+    }
+}
+
+// Writes "CA/<ca>/crl.pem" with every certificate revoked by CA
+pub fn write_crl(store: &dyn Store, config: &Config, ca_bundle: &Bundle, authority: Authority) -> Result<(), Error> {
+    let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+    let database = Database::open(store, &ca_dir)?;
+
+    // CRL number is kept in hex like "openssl ca" does
+    let crl_number_filename = format!("{}/crlnumber", &ca_dir);
+    let crl_number = match store.exists(&crl_number_filename) {
+        true => {
+            let contents = String::from_utf8_lossy(&store.read(&crl_number_filename)?).into_owned();
+            match BigNum::from_hex_str(contents.trim()) {
+                Ok(crl_number) => crl_number,
+                Err(err) => {
+                    return Err(Error::Index(format!(
+                        "Unable to read CRL number from {}: {}",
+                        &crl_number_filename, err
+                    )));
+                }
+            }
+        }
+        false => BigNum::from_u32(1)?,
+    };
+
+    let crl = gen_crl(&ca_bundle, &database, &crl_number, config.ca.crl_next_update_days)?;
+    let crl_filename = format!("{}/crl.pem", &ca_dir);
+    store.write(&crl_filename, &crl)?;
+    println!("CRL written: {}", &crl_filename);
+
+    let one = BigNum::from_u32(1)?;
+    let mut next_crl_number = BigNum::new()?;
+    next_crl_number.checked_add(&crl_number, &one)?;
+    store.write(&crl_number_filename, format!("{}\n", serial_hex(&next_crl_number)?).as_bytes())?;
+    Ok(())
+}
+
+pub fn write_crls(store: &dyn Store, config: &Config, ca: &CA) -> Result<(), Error> {
+    for authority in Authority::all() {
+        write_crl(store, &config, ca.bundle(authority), authority)?;
+    }
+    Ok(())
+}
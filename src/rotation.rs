@@ -0,0 +1,206 @@
+use cert_machine::{
+    all_cert_types, current_cert, gen_ca_cert, gen_main_ca_cert, link_ca_certs, profile, serial_hex, store_bundle,
+    write_kubeconfig, Authority, Bundle, Config, Database, Error, Store, CA,
+};
+use kubernetes_certs::reissue_cert;
+use openssl::hash::MessageDigest;
+use revocation::write_crl;
+
+// Rotation of CA is done in three steps, like Kubernetes manual CA rotation:
+// 1. rotate_ca creates successor and makes every component trust both old and new CA,
+// 2. reissue_certs reissues leaf certificates by new CA once trust bundles are distributed,
+// 3. finalize_rotation drops old CA from trust bundles once new certificates are deployed.
+
+// Replaces CA by successor. Until rotation is finalized, clients trust bundle of old and new CA
+// certificates. Leaf certificates are still issued by old CA.
+pub fn rotate_ca(store: &dyn Store, config: &Config, authority: Authority) -> Result<(), Error> {
+    let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+    let bundle_filename = format!("{}/ca-bundle.crt", &ca_dir);
+    if store.exists(&bundle_filename) {
+        return Err(Error::Policy(format!(
+            "Rotation of {} CA is in progress, finalize it first",
+            authority.dir_name()
+        )));
+    }
+    let ca = CA::read(store, &config.out_dir)?;
+    let old_ca = ca.bundle(authority);
+
+    println!("Creating successor of {} CA", authority.dir_name());
+    let new_ca = match authority {
+        Authority::Root => gen_main_ca_cert(&config)?,
+        Authority::Etcd | Authority::FrontProxy => gen_ca_cert(authority, Some(&ca.main_ca), &config)?,
+    };
+    // Token key labeled "<key_label>-<ca>" is picked again instead of generating new one
+    if new_ca.cert.public_key()?.public_eq(&*old_ca.cert.public_key()?) {
+        return Err(Error::Policy(format!(
+            "Successor of {} CA got key of its predecessor, remove or relabel that key in PKCS#11 token",
+            authority.dir_name()
+        )));
+    }
+
+    // Old CA is kept as "ca-old" until rotation is finalized
+    archive_ca(store, &ca_dir, &old_ca, "ca", "ca-old")?;
+    store_bundle(store, &new_ca, &ca_dir, "ca", config.overwrite)?;
+    let mut trust = new_ca.cert.to_pem()?;
+    trust.extend(old_ca.cert.to_pem()?);
+    store.write(&bundle_filename, &trust)?;
+    println!("Trust bundle written: {}", &bundle_filename);
+    link_ca_certs(store, &config)?;
+    update_kubeconfigs(store, &config, authority)?;
+    write_crl(store, &config, &new_ca, authority)?;
+    println!(
+        "Distribute trust bundles to all components, then run \"rotate-ca {} --reissue\"",
+        authority.dir_name()
+    );
+    Ok(())
+}
+
+// Reissues every leaf certificate of CA being rotated by its successor
+pub fn reissue_certs(store: &dyn Store, config: &Config, authority: Authority) -> Result<(), Error> {
+    let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+    if !store.exists(&format!("{}/ca-bundle.crt", &ca_dir)) {
+        return Err(Error::Policy(format!(
+            "{} CA isn't being rotated, run \"rotate-ca {}\" first",
+            authority.dir_name(),
+            authority.dir_name()
+        )));
+    }
+    let ca = CA::read(store, &config.out_dir)?;
+    for cert_type in all_cert_types(&config) {
+        if profile(&config, &cert_type).authority == authority {
+            reissue_cert(store, &ca, &config, &cert_type, None)?;
+        }
+    }
+    println!(
+        "Deploy new certificates to all components, then run \"rotate-ca {} --finalize\"",
+        authority.dir_name()
+    );
+    Ok(())
+}
+
+// Drops old CA from trust bundles. Old CA certificate and key are kept as "ca-<fingerprint>".
+pub fn finalize_rotation(store: &dyn Store, config: &Config, authority: Authority) -> Result<(), Error> {
+    let ca_dir = format!("{}/CA/{}", &config.out_dir, authority.dir_name());
+    let bundle_filename = format!("{}/ca-bundle.crt", &ca_dir);
+    if !store.exists(&bundle_filename) {
+        return Err(Error::Policy(format!("{} CA isn't being rotated", authority.dir_name())));
+    }
+
+    // Certificates still issued by old CA stop being trusted
+    let ca = CA::read(store, &config.out_dir)?;
+    let ca_public_key = ca.bundle(authority).cert.public_key()?;
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        if profile.authority != authority {
+            continue;
+        }
+        if let Some(bundle) = current_cert(store, &profile)? {
+            if !bundle.cert.verify(&ca_public_key).unwrap_or(false) {
+                return Err(Error::Policy(format!(
+                    "Cert for {} is issued by old {} CA, run \"rotate-ca {} --reissue\" first",
+                    &profile.description,
+                    authority.dir_name(),
+                    authority.dir_name()
+                )));
+            }
+        }
+    }
+
+    let old_ca = store.read_bundle(&ca_dir, "ca-old")?;
+    let fingerprint = old_ca.cert.digest(MessageDigest::sha256())?;
+    let old_name = format!("ca-{}", hex(&fingerprint));
+    archive_ca(store, &ca_dir, &old_ca, "ca-old", &old_name)?;
+    store.remove(&bundle_filename)?;
+    link_ca_certs(store, &config)?;
+    update_kubeconfigs(store, &config, authority)?;
+    println!("Rotation of {} CA is finalized", authority.dir_name());
+    Ok(())
+}
+
+// Moves CA certificate and key to new name in CA directory and points its database entry there
+fn archive_ca(store: &dyn Store, ca_dir: &str, ca: &Bundle, from: &str, to: &str) -> Result<(), Error> {
+    store.write_bundle(&ca_dir, &to, &ca)?;
+    store.remove(&format!("{}/certs/{}.crt", &ca_dir, &from))?;
+    store.remove(&format!("{}/keys/{}.key", &ca_dir, &from))?;
+
+    let serial = ca.cert.serial_number().to_bn()?;
+    let serial = serial_hex(&serial)?;
+    let mut database = Database::open(store, &ca_dir)?;
+    if let Some(entry) = database.entries.iter_mut().find(|entry| entry.serial == serial) {
+        entry.filename = format!("certs/{}.crt", &to);
+    }
+    database.save(store)
+}
+
+// Kubeconfigs embed trusted certificates, they are rewritten when trust bundle changes
+fn update_kubeconfigs(store: &dyn Store, config: &Config, authority: Authority) -> Result<(), Error> {
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        if profile.authority != authority || profile.kubeconfig.is_none() {
+            continue;
+        }
+        if let Some(bundle) = current_cert(store, &profile)? {
+            println!("Updating kubeconfig for {}", &profile.description);
+            write_kubeconfig(store, &profile, &bundle)?;
+        }
+    }
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::{CertType, MemoryStore};
+    use create_ca;
+    use kubernetes_certs::gen_cert;
+    use openssl::x509::X509;
+    use testing;
+
+    #[test]
+    fn rotate_root_ca() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let store = MemoryStore::new();
+        let ca = create_ca(&store, &config).unwrap();
+        for cert_type in all_cert_types(&config) {
+            gen_cert(&store, &ca, &config, &cert_type).unwrap();
+        }
+        let ca_dir = "out/CA/root";
+        let old_ca = ca.bundle(Authority::Root).cert.to_der().unwrap();
+        let apiserver = profile(&config, &CertType::ApiServer);
+
+        rotate_ca(&store, &config, Authority::Root).unwrap();
+        let new_ca = store.read_cert(&ca_dir, "ca").unwrap().cert;
+        assert_ne!(new_ca.to_der().unwrap(), old_ca);
+        let mut trust = new_ca.to_pem().unwrap();
+        trust.extend(X509::from_der(&old_ca).unwrap().to_pem().unwrap());
+        assert_eq!(store.read("out/CA/root/ca-bundle.crt").unwrap(), trust);
+        assert_eq!(store.read_cert(&ca_dir, "ca-old").unwrap().cert.to_der().unwrap(), old_ca);
+        assert!(rotate_ca(&store, &config, Authority::Root).is_err());
+        assert!(finalize_rotation(&store, &config, Authority::Root).is_err());
+
+        reissue_certs(&store, &config, Authority::Root).unwrap();
+        let leaf = current_cert(&store, &apiserver).unwrap().unwrap().cert;
+        assert!(leaf.verify(&new_ca.public_key().unwrap()).unwrap());
+        assert!(!leaf.verify(&ca.bundle(Authority::Root).cert.public_key().unwrap()).unwrap());
+        // Other CAs keep their certificates
+        let etcd_client = profile(&config, &CertType::ApiServerEtcdClient);
+        let etcd_leaf = current_cert(&store, &etcd_client).unwrap().unwrap().cert;
+        assert!(etcd_leaf.verify(&ca.bundle(Authority::Etcd).cert.public_key().unwrap()).unwrap());
+
+        finalize_rotation(&store, &config, Authority::Root).unwrap();
+        assert!(!store.exists("out/CA/root/ca-bundle.crt"));
+        assert!(!store.exists("out/CA/root/certs/ca-old.crt"));
+        assert!(!store.exists("out/CA/root/keys/ca-old.key"));
+        let fingerprint = X509::from_der(&old_ca).unwrap().digest(MessageDigest::sha256()).unwrap();
+        let archived = format!("ca-{}", hex(&fingerprint));
+        let archived_bundle = store.read_bundle(&ca_dir, &archived).unwrap();
+        assert_eq!(archived_bundle.cert.to_der().unwrap(), old_ca);
+        let database = Database::open(&store, &ca_dir).unwrap();
+        let old_serial = archived_bundle.cert.serial_number().to_bn().unwrap();
+        assert_eq!(database.find(&old_serial).unwrap().unwrap().filename, format!("certs/{}.crt", &archived));
+    }
+}
@@ -0,0 +1,576 @@
+use openssl::x509::X509;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{symlink, OpenOptionsExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use {AltName, Bundle, Entry, Error};
+
+// Storage of certificates, keys, CA databases and kubeconfigs.
+// Names are paths in layout of output directory, e.g. "<out_dir>/CA/root/certs/ca.crt".
+pub trait Store {
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error>;
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error>;
+    // Readers never see partially written contents, e.g. metrics picked up by node_exporter
+    fn write_atomic(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        self.write(&name, &data)
+    }
+    fn exists(&self, name: &str) -> bool;
+    fn remove(&self, name: &str) -> Result<(), Error>;
+    // Makes name refer to target, relative target is resolved from directory of name like symlink one.
+    // Existing link is replaced, existing regular file is an error.
+    fn link(&self, target: &str, name: &str) -> Result<(), Error>;
+    fn is_link(&self, name: &str) -> bool;
+    // Follows links to name of stored file, name which is not a link is returned normalized
+    fn resolve(&self, name: &str) -> Result<String, Error>;
+    // Files and links in dir and its subdirectories, relative to dir and sorted
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error>;
+    // Directory expected by cluster components even if nothing is written into it
+    fn create_dir(&self, _name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Reads "<ca_dir>/certs/<name>.crt" and "<ca_dir>/keys/<name>.key"
+    fn read_bundle(&self, ca_dir: &str, name: &str) -> Result<Box<Bundle>, Error> {
+        let mut bundle = self.read_cert(&ca_dir, &name)?;
+        bundle.key = self.read(&format!("{}/keys/{}.key", &ca_dir, &name))?;
+        Ok(bundle)
+    }
+
+    // Reads only certificate, key of returned bundle is empty
+    fn read_cert(&self, ca_dir: &str, name: &str) -> Result<Box<Bundle>, Error> {
+        let cert = self.read(&format!("{}/certs/{}.crt", &ca_dir, &name))?;
+        Ok(Box::new(Bundle {
+            cert: X509::from_pem(&cert)?,
+            key: Vec::new(),
+        }))
+    }
+
+    // Writes certificate and key if bundle has one
+    fn write_bundle(&self, ca_dir: &str, name: &str, bundle: &Bundle) -> Result<(), Error> {
+        self.write(&format!("{}/certs/{}.crt", &ca_dir, &name), &bundle.to_pem()?)?;
+        if !bundle.key.is_empty() {
+            self.write(&format!("{}/keys/{}.key", &ca_dir, &name), &bundle.key)?;
+        }
+        Ok(())
+    }
+}
+
+// Local directory tree, links are symlinks
+pub struct FileStore;
+
+fn is_private_key(name: &str) -> bool {
+    Path::new(&name).extension().map_or(false, |ext| ext == "key")
+}
+
+// Private keys are readable by owner only, also when existing file is overwritten
+fn write_file(name: &str, data: &[u8], private: bool) -> Result<(), Error> {
+    create_parent_dir(&name)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        options.mode(0o600);
+    }
+    let mut file = options.open(&name)?;
+    if private {
+        file.set_permissions(Permissions::from_mode(0o600))?;
+    }
+    Ok(file.write_all(&data)?)
+}
+
+impl Store for FileStore {
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        Ok(fs::read(&name)?)
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        write_file(&name, &data, is_private_key(&name))
+    }
+
+    fn write_atomic(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let tmp_name = format!("{}.tmp", &name);
+        write_file(&tmp_name, &data, is_private_key(&name))?;
+        Ok(fs::rename(&tmp_name, &name)?)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        Path::new(&name).exists()
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        Ok(fs::remove_file(&name)?)
+    }
+
+    fn link(&self, target: &str, name: &str) -> Result<(), Error> {
+        create_parent_dir(&name)?;
+        if let Err(err) = symlink(&target, &name) {
+            let metadata = fs::symlink_metadata(&name)?;
+            match metadata.file_type().is_symlink() {
+                true => {
+                    fs::remove_file(&name)?;
+                    symlink(&target, &name)?;
+                }
+                false => {
+                    return Err(Error::Policy(format!(
+                        "Unable to create symlink. \"{}\" exists and not a symlink: {}",
+                        &name, &err
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_link(&self, name: &str) -> bool {
+        fs::symlink_metadata(&name).map(|metadata| metadata.file_type().is_symlink()).unwrap_or(false)
+    }
+
+    fn resolve(&self, name: &str) -> Result<String, Error> {
+        let mut name = normalize(Path::new(&name));
+        for _ in 0..MAX_LINKS {
+            let target = match fs::read_link(&name) {
+                Ok(target) => target,
+                Err(_) => return Ok(name),
+            };
+            let parent = Path::new(&name).parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+            name = normalize(&parent.join(&target));
+        }
+        Err(Error::Policy(format!("Too many levels of links: {}", &name)))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let mut names = vec![];
+        if Path::new(&dir).is_dir() {
+            list_dir(Path::new(&dir), Path::new(&dir), &mut names)?;
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn create_dir(&self, name: &str) -> Result<(), Error> {
+        Ok(fs::create_dir_all(&name)?)
+    }
+}
+
+// Linked directories are not descended into, links are listed as they are
+fn list_dir(root: &Path, dir: &Path, names: &mut Vec<String>) -> Result<(), Error> {
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if fs::symlink_metadata(&path)?.is_dir() {
+            list_dir(&root, &path, names)?;
+        } else if let Ok(name) = path.strip_prefix(&root) {
+            names.push(name.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+fn create_parent_dir(name: &str) -> Result<(), Error> {
+    if let Some(dir) = Path::new(&name).parent() {
+        if !dir.as_os_str().is_empty() {
+            fs::create_dir_all(&dir)?;
+        }
+    }
+    Ok(())
+}
+
+// Keeps everything in memory, useful for tests and for embedding cert_machine
+#[derive(Default)]
+pub struct MemoryStore {
+    files: RefCell<HashMap<String, Vec<u8>>>,
+    links: RefCell<HashMap<String, String>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    // Names of all stored files, links are not included
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.files.borrow().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Names of files and links in dir with prefix of dir stripped
+    fn names_in(&self, dir: &str) -> Vec<String> {
+        let dir = normalize(Path::new(&dir));
+        let files = self.files.borrow();
+        let links = self.links.borrow();
+        let mut names = vec![];
+        for name in files.keys().chain(links.keys()) {
+            if let Ok(name) = Path::new(&name).strip_prefix(&dir) {
+                if !name.as_os_str().is_empty() {
+                    names.push(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+        names
+    }
+}
+
+impl Store for MemoryStore {
+    fn resolve(&self, name: &str) -> Result<String, Error> {
+        let links = self.links.borrow();
+        let mut name = normalize(Path::new(&name));
+        for _ in 0..MAX_LINKS {
+            let target = match links.get(&name) {
+                Some(target) => target,
+                None => return Ok(name),
+            };
+            let parent = Path::new(&name).parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+            name = normalize(&parent.join(&target));
+        }
+        Err(Error::Policy(format!("Too many levels of links: {}", &name)))
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let mut names = self.names_in(&dir);
+        names.sort();
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let resolved = self.resolve(&name)?;
+        match self.files.borrow().get(&resolved) {
+            Some(data) => Ok(data.clone()),
+            None => Err(Error::Policy(format!("{} does not exists in store", &name))),
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let resolved = self.resolve(&name)?;
+        self.files.borrow_mut().insert(resolved, data.to_vec());
+        Ok(())
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        match self.resolve(&name) {
+            Ok(resolved) => self.files.borrow().contains_key(&resolved),
+            Err(_) => false,
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        let name = normalize(Path::new(&name));
+        if self.links.borrow_mut().remove(&name).is_some() || self.files.borrow_mut().remove(&name).is_some() {
+            return Ok(());
+        }
+        Err(Error::Policy(format!("{} does not exists in store", &name)))
+    }
+
+    fn link(&self, target: &str, name: &str) -> Result<(), Error> {
+        let name = normalize(Path::new(&name));
+        if self.files.borrow().contains_key(&name) {
+            return Err(Error::Policy(format!(
+                "Unable to create link. \"{}\" exists and not a link",
+                &name
+            )));
+        }
+        self.links.borrow_mut().insert(name, target.to_owned());
+        Ok(())
+    }
+
+    fn is_link(&self, name: &str) -> bool {
+        self.links.borrow().contains_key(&normalize(Path::new(&name)))
+    }
+}
+
+// Prints plan of changes instead of making them. Changes are kept in memory,
+// so later reads see files and links planned earlier.
+pub struct DryRunStore<'a> {
+    store: &'a dyn Store,
+    planned: MemoryStore,
+    removed: RefCell<HashSet<String>>,
+    printed: RefCell<HashSet<String>>,
+}
+
+impl<'a> DryRunStore<'a> {
+    pub fn new(store: &'a dyn Store) -> DryRunStore<'a> {
+        DryRunStore {
+            store,
+            planned: MemoryStore::new(),
+            removed: RefCell::new(HashSet::new()),
+            printed: RefCell::new(HashSet::new()),
+        }
+    }
+
+    // Every change is printed once, e.g. database rewritten for each issued cert is one "replace"
+    fn plan(&self, action: &str, name: &str, details: &str) {
+        let line = format!("{:<10} {}{}", action, name, details);
+        if self.printed.borrow_mut().insert(line.clone()) {
+            println!("{}", &line);
+        }
+    }
+
+    // Name is in underlying store and not removed by plan
+    fn in_store(&self, name: &str) -> bool {
+        !self.removed.borrow().contains(name) && (self.store.exists(&name) || self.store.is_link(&name))
+    }
+}
+
+impl<'a> Store for DryRunStore<'a> {
+    fn read(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let resolved = self.planned.resolve(&name)?;
+        match self.planned.files.borrow().get(&resolved) {
+            Some(data) => Ok(data.clone()),
+            None if self.in_store(&resolved) => self.store.read(&resolved),
+            None => Err(Error::Policy(format!("{} does not exists in store", &name))),
+        }
+    }
+
+    fn write(&self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let resolved = self.planned.resolve(&name)?;
+        let action = match self.in_store(&resolved) {
+            true => "replace",
+            false => "create",
+        };
+        self.plan(action, &resolved, "");
+        if let Ok(cert) = X509::from_pem(&data) {
+            println!("{:<10} subject: {}", "", Entry::from_cert(&cert, "")?.subject);
+            let san: Vec<String> = AltName::from_cert(&cert).iter().map(|name| name.to_string()).collect();
+            if !san.is_empty() {
+                println!("{:<10} SAN: {}", "", san.join(", "));
+            }
+        }
+        self.removed.borrow_mut().remove(&resolved);
+        self.planned.write(&resolved, &data)
+    }
+
+    fn exists(&self, name: &str) -> bool {
+        match self.planned.resolve(&name) {
+            Ok(resolved) => self.planned.files.borrow().contains_key(&resolved) || self.in_store(&resolved),
+            Err(_) => false,
+        }
+    }
+
+    fn remove(&self, name: &str) -> Result<(), Error> {
+        let name = normalize(Path::new(&name));
+        let planned = self.planned.remove(&name).is_ok();
+        if self.in_store(&name) {
+            self.plan("remove", &name, "");
+            self.removed.borrow_mut().insert(name);
+            return Ok(());
+        }
+        match planned {
+            true => Ok(()),
+            false => Err(Error::Policy(format!("{} does not exists in store", &name))),
+        }
+    }
+
+    fn link(&self, target: &str, name: &str) -> Result<(), Error> {
+        let name = normalize(Path::new(&name));
+        if self.in_store(&name) && !self.store.is_link(&name) {
+            return Err(Error::Policy(format!(
+                "Unable to create symlink. \"{}\" exists and not a symlink",
+                &name
+            )));
+        }
+        let action = match self.is_link(&name) {
+            true => "re-symlink",
+            false => "symlink",
+        };
+        self.plan(action, &name, &format!(" -> {}", &target));
+        self.planned.link(&target, &name)
+    }
+
+    fn is_link(&self, name: &str) -> bool {
+        let name = normalize(Path::new(&name));
+        self.planned.is_link(&name) || (self.in_store(&name) && self.store.is_link(&name))
+    }
+
+    fn resolve(&self, name: &str) -> Result<String, Error> {
+        let resolved = self.planned.resolve(&name)?;
+        match self.planned.files.borrow().contains_key(&resolved) || !self.in_store(&resolved) {
+            true => Ok(resolved),
+            false => self.store.resolve(&resolved),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>, Error> {
+        let mut names: Vec<String> = self
+            .store
+            .list(&dir)?
+            .into_iter()
+            .filter(|name| !self.removed.borrow().contains(&normalize(&Path::new(&dir).join(&name))))
+            .collect();
+        names.extend(self.planned.names_in(&dir));
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn create_dir(&self, name: &str) -> Result<(), Error> {
+        if !self.store.exists(&name) {
+            self.plan("mkdir", &normalize(Path::new(&name)), "");
+        }
+        Ok(())
+    }
+}
+
+// Same limit of nested links as Linux has
+const MAX_LINKS: usize = 40;
+
+// Drops "." and resolves ".." without touching filesystem
+fn normalize(path: &Path) -> String {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component.as_os_str()),
+        }
+    }
+    normalized.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use install::{issue_profile, store_bundle};
+    use kubernetes::{profile, Authority, CertType, CA};
+    use std::env;
+    use std::process;
+    use testing;
+    use {Config, Database, Status};
+
+    #[test]
+    fn normalized_paths() {
+        assert_eq!(normalize(Path::new("out/master/../CA/root/./certs/ca.crt")), "out/CA/root/certs/ca.crt");
+        assert_eq!(normalize(Path::new("out/s1/../../x")), "x");
+        assert_eq!(normalize(Path::new("../CA/root")), "../CA/root");
+        assert_eq!(normalize(Path::new("./out//master/")), "out/master");
+    }
+
+    #[test]
+    fn memory_links() {
+        let store = MemoryStore::new();
+        store.write("out/CA/root/certs/a.crt", b"a").unwrap();
+        store.link("../CA/root/certs/a.crt", "out/master/a.crt").unwrap();
+        store.link("master/a.crt", "out/b.crt").unwrap();
+        assert!(store.is_link("out/master/a.crt"));
+        assert!(!store.is_link("out/CA/root/certs/a.crt"));
+        assert_eq!(store.read("out/b.crt").unwrap(), b"a");
+
+        // Writes through link change its target
+        store.write("out/master/a.crt", b"b").unwrap();
+        assert_eq!(store.read("out/CA/root/certs/a.crt").unwrap(), b"b");
+        assert_eq!(store.names(), vec!["out/CA/root/certs/a.crt".to_owned()]);
+
+        // Dangling link doesn't exist
+        store.link("../nothing", "out/master/c.crt").unwrap();
+        assert!(!store.exists("out/master/c.crt"));
+        assert!(store.read("out/master/c.crt").is_err());
+
+        assert!(store.link("x", "out/CA/root/certs/a.crt").is_err());
+        store.remove("out/master/a.crt").unwrap();
+        assert!(!store.exists("out/b.crt"));
+        assert!(store.exists("out/CA/root/certs/a.crt"));
+
+        store.link("a", "loop").unwrap();
+        store.link("loop", "a").unwrap();
+        assert!(store.read("loop").is_err());
+    }
+
+    #[test]
+    fn list_and_resolve() {
+        let memory_store = MemoryStore::new();
+        let dir = env::temp_dir().join(format!("cert-machine-test-{}", process::id()));
+        let file_dir = dir.to_string_lossy().into_owned();
+        let stores: [(&dyn Store, &str); 2] = [(&memory_store, "out"), (&FileStore, &file_dir)];
+        for &(store, out_dir) in stores.iter() {
+            store.write(&format!("{}/CA/root/certs/a.crt", out_dir), b"a").unwrap();
+            store.link("../CA/root/certs/a.crt", &format!("{}/master/a.crt", out_dir)).unwrap();
+            store.link("master/a.crt", &format!("{}/b.crt", out_dir)).unwrap();
+            assert_eq!(store.list(&out_dir).unwrap(), vec!["CA/root/certs/a.crt", "b.crt", "master/a.crt"]);
+            assert_eq!(store.list(&format!("{}/master", out_dir)).unwrap(), vec!["a.crt"]);
+            assert!(store.list(&format!("{}/nothing", out_dir)).unwrap().is_empty());
+            let target = format!("{}/CA/root/certs/a.crt", out_dir);
+            assert_eq!(store.resolve(&format!("{}/b.crt", out_dir)).unwrap(), target);
+            assert_eq!(store.resolve(&format!("{}/./CA/root/certs/a.crt", out_dir)).unwrap(), target);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn key_file_mode() {
+        let dir = env::temp_dir().join(format!("cert-machine-mode-{}", process::id()));
+        let key = dir.join("a.key").to_string_lossy().into_owned();
+        let crt = dir.join("a.crt").to_string_lossy().into_owned();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&key, b"old").unwrap();
+        fs::set_permissions(&key, Permissions::from_mode(0o644)).unwrap();
+        FileStore.write(&key, b"key").unwrap();
+        FileStore.write(&crt, b"crt").unwrap();
+        let mode = |name: &str| fs::metadata(&name).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&key), 0o600);
+        assert_eq!(fs::read(&key).unwrap(), b"key");
+        assert_ne!(mode(&crt), 0o600);
+        FileStore.write_atomic(&key, b"new").unwrap();
+        assert_eq!(mode(&key), 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_list() {
+        let store = MemoryStore::new();
+        store.write("out/a.crt", b"a").unwrap();
+        store.write("out/b.crt", b"b").unwrap();
+        let dry_run = DryRunStore::new(&store);
+        dry_run.remove("out/a.crt").unwrap();
+        dry_run.write("out/c.crt", b"c").unwrap();
+        dry_run.link("c.crt", "out/d.crt").unwrap();
+        assert_eq!(dry_run.list("out").unwrap(), vec!["b.crt", "c.crt", "d.crt"]);
+        assert_eq!(dry_run.resolve("out/d.crt").unwrap(), "out/c.crt");
+        assert_eq!(store.list("out").unwrap(), vec!["a.crt", "b.crt"]);
+    }
+
+    #[test]
+    fn issue_into_memory() {
+        let config = Config::from_toml(&testing::config("")).unwrap();
+        let store = MemoryStore::new();
+        let ca = CA::generate(&config).unwrap();
+        for authority in Authority::all() {
+            let ca_dir = format!("out/CA/{}", authority.dir_name());
+            store_bundle(&store, ca.bundle(authority), &ca_dir, "ca", false).unwrap();
+        }
+
+        let apiserver = profile(&config, &CertType::ApiServer);
+        let bundle = issue_profile(&store, &ca, &apiserver, None, None).unwrap();
+        let sn = bundle.cert.serial_number().to_bn().unwrap();
+        let crt_filename = format!("out/CA/root/certs/apiserver-{}.crt", sn);
+        let key_filename = format!("out/CA/root/keys/apiserver-{}.key", sn);
+        assert_eq!(store.read(&crt_filename).unwrap(), bundle.to_pem().unwrap());
+        assert_eq!(store.read(&key_filename).unwrap(), bundle.key);
+        assert!(store.is_link("out/master/apiserver.crt"));
+        assert!(store.is_link("out/master/apiserver.key"));
+        assert_eq!(store.read("out/master/apiserver.crt").unwrap(), store.read(&crt_filename).unwrap());
+        assert_eq!(store.read("out/master/apiserver.key").unwrap(), bundle.key);
+
+        let ca_public_key = ca.main_ca.cert.public_key().unwrap();
+        assert!(bundle.cert.verify(&ca_public_key).unwrap());
+        assert!(apiserver.mismatches(&bundle.cert, Some(&ca.main_ca.cert)).unwrap().is_empty());
+
+        let database = Database::open(&store, "out/CA/root").unwrap();
+        let entry = database.find(&sn).unwrap().unwrap();
+        assert_eq!(entry.status, Status::Valid);
+        assert_eq!(entry.filename, format!("certs/apiserver-{}.crt", sn));
+        assert!(entry.has_common_name("kubernetes"));
+        assert_eq!(database.entries.len(), 2);
+        assert!(store.exists("out/CA/root/index.txt.attr"));
+
+        let admin = profile(&config, &CertType::Admin);
+        issue_profile(&store, &ca, &admin, None, None).unwrap();
+        let kubeconfig = String::from_utf8(store.read("out/users/admin.kubeconfig").unwrap()).unwrap();
+        assert!(kubeconfig.contains("192.0.2.1:6443"));
+        assert_eq!(Database::open(&store, "out/CA/root").unwrap().entries.len(), 3);
+        // Nothing is written outside of output directory
+        assert!(store.names().iter().all(|name| name.starts_with("out/")));
+    }
+}
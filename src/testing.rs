@@ -0,0 +1,29 @@
+// Config shared by tests: one worker, one etcd server and ECDSA keys, so CAs are generated fast.
+// Output goes to "out". Extra top-level settings and tables are inserted before [[worker]].
+pub fn config(extra: &str) -> String {
+    format!(
+        r#"
+cluster_name = "test"
+validity_days = 30
+key_algorithm = "ecdsa-p256"
+master_san = ["10.0.0.1", "master.test"]
+apiserver_internal_address = "10.0.0.1:6443"
+apiserver_external_address = "192.0.2.1:6443"
+out_dir = "out"
+{}
+
+[[worker]]
+hostname = "s1"
+san = ["10.0.22.2", "s1"]
+
+[[etcd_server]]
+hostname = "e1"
+san = ["10.0.23.1", "e1"]
+
+[ca]
+validity_days = 100
+key_algorithm = "ecdsa-p256"
+"#,
+        extra
+    )
+}
@@ -0,0 +1,66 @@
+use cert_machine::{issue_profile, profile, CertType, Config, Database, Error, Store, User, CA};
+use std::u32;
+
+// Issues user certificate which expires after given number of seconds and writes its kubeconfig.
+// Issuance is recorded with requester and TTL in audit log of root CA database.
+pub fn issue_user(
+    store: &dyn Store,
+    ca: &CA,
+    config: &Config,
+    user: &User,
+    ttl: i64,
+    requester: &str,
+) -> Result<(), Error> {
+    if ttl <= 0 || ttl > u32::MAX as i64 {
+        return Err(Error::Config(format!("Invalid TTL of user certificate: {} seconds", ttl)));
+    }
+    // Requester is a field of tab separated audit line, checked before certificate is issued
+    if requester.chars().any(|c| c.is_control()) {
+        return Err(Error::Config(format!("Requester must not contain control characters: {:?}", requester)));
+    }
+    let cert_type = CertType::User(&user);
+    let mut profile = profile(&config, &cert_type);
+    profile.validity_seconds = Some(ttl as u32);
+    println!("Creating cert for {} valid for {} seconds", &profile.description, ttl);
+    let bundle = issue_profile(store, &ca, &profile, None, None)?;
+
+    let serial = bundle.cert.serial_number().to_bn()?;
+    Database::open(store, &profile.ca_dir())?.audit(store, &serial, &requester, ttl)?;
+    if let Some(ref kubeconfig) = profile.kubeconfig {
+        println!("Kubeconfig written: {}", &kubeconfig.filename);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cert_machine::MemoryStore;
+    use create_ca;
+    use testing;
+
+    #[test]
+    fn requester_in_audit() {
+        let user = "[[user]]\nusername = \"dev\"\ngroup = [\"developers\"]";
+        let config = Config::from_toml(&testing::config(&user)).unwrap();
+        let user = &config.user.as_ref().unwrap()[0];
+        let store = MemoryStore::new();
+        let ca = create_ca(&store, &config).unwrap();
+        let ca_dir = "out/CA/root";
+        let entries = Database::open(&store, &ca_dir).unwrap().entries.len();
+
+        for requester in ["alice\tbob", "alice\n20300101000000Z", "alice\r"].iter() {
+            assert!(issue_user(&store, &ca, &config, &user, 3600, &requester).is_err());
+        }
+        let database = Database::open(&store, &ca_dir).unwrap();
+        assert_eq!(database.entries.len(), entries);
+        assert!(database.audit_records(&store).unwrap().is_empty());
+
+        issue_user(&store, &ca, &config, &user, 3600, "alice@example.com").unwrap();
+        let database = Database::open(&store, &ca_dir).unwrap();
+        let records = database.audit_records(&store).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].requester, "alice@example.com");
+        assert_eq!(records[0].ttl, 3600);
+    }
+}
@@ -0,0 +1,47 @@
+use cert_machine::{all_cert_types, current_cert, profile, Config, Error, Store};
+use openssl::x509::X509;
+
+// Compares certificates in use with subject, SAN, key usages and issuer derived from config.
+// Returns number of certificates which are missing or differ from config.
+pub fn verify(store: &dyn Store, config: &Config) -> Result<u32, Error> {
+    let mut drifted = 0;
+    for cert_type in all_cert_types(&config) {
+        let profile = profile(&config, &cert_type);
+        // Certificate in use is one symlink points to, others are found in CA database
+        let (filename, cert) = match profile.symlink {
+            Some(ref symlink) if store.exists(&format!("{}.crt", &symlink)) => {
+                let filename = format!("{}.crt", &symlink);
+                let cert = X509::from_pem(&store.read(&filename)?)?;
+                (filename, cert)
+            }
+            _ => match current_cert(store, &profile)? {
+                Some(bundle) => {
+                    let sn = bundle.cert.serial_number().to_bn()?;
+                    (format!("{}/certs/{}-{}.crt", &profile.ca_dir(), &profile.filename, sn), bundle.cert)
+                }
+                None => {
+                    println!("{}: no valid cert found", &profile.description);
+                    drifted += 1;
+                    continue;
+                }
+            },
+        };
+        let ca_cert = store.read_cert(&profile.ca_dir(), "ca")?.cert;
+        let mismatches = profile.mismatches(&cert, Some(&ca_cert))?;
+        if mismatches.is_empty() {
+            continue;
+        }
+        println!("{}: {}", &profile.description, &filename);
+        for mismatch in mismatches.iter() {
+            println!("    {} differs", mismatch.field.name());
+            println!("        expected: {}", &mismatch.expected);
+            println!("        actual:   {}", &mismatch.actual);
+        }
+        drifted += 1;
+    }
+    match drifted {
+        0 => println!("All certificates match config"),
+        _ => println!("{} certificates differ from config", drifted),
+    }
+    Ok(drifted)
+}